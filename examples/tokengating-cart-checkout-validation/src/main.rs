@@ -1,33 +1,186 @@
+use std::collections::HashSet;
 use std::str::FromStr;
 
 use shopify_function::prelude::*;
 use shopify_function::Result;
 
-use hex;
+use ed25519_compact::{PublicKey, Signature};
+use hkdf::Hkdf;
 use hmac::NewMac;
 use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
+use subtle::ConstantTimeEq;
 
+use error::GateError;
 use output::FunctionError;
 
+// The discount function's decoder, so that both functions read the same compact
+// gate contexts
+#[path = "../../../extensions/tokengating-function/src/compact.rs"]
+mod compact;
+mod error;
+
 generate_types!(
     query_path = "./input.graphql",
     schema_path = "./schema.graphql"
 );
 
-const SECRET_KEY: &str = "secret-key"; // This should be a secret key that is shared between the app and the function
-
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
 pub struct GateContextItem {
     pub id: Option<ID>,
+    // Gate configurations unlocked by an aggregate item, instead of a single `id`
+    pub ids: Option<Vec<ID>>,
     pub hmac: Option<String>,
+    // Set for items decoded from the compact encoding, see `compact`
+    #[serde(skip)]
+    pub hmac_truncated: bool,
+    // Hex encoded Ed25519 signature, an alternative to `hmac`
+    pub ed25519: Option<String>,
+    pub kid: Option<String>,
+    // Version of the signed message, see `signed_message`
+    pub v: Option<u8>,
+    pub binding: Option<BuyerBinding>,
+    // Wallet that proved ownership of the unlocking tokens
+    pub address: Option<String>,
+    // Unix timestamps in seconds
+    pub issued_at: Option<i64>,
+    pub expires_at: Option<i64>,
+    // Identifies the item in the revocation list, see `RevocationList`
+    pub nonce: Option<String>,
+    // How many qualifying tokens the wallet holds. Only the discount function uses
+    // it, but it is part of the signed message.
+    pub token_count: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum BuyerBinding {
+    #[default]
+    None,
+    Customer,
+    Email,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FunctionConfiguration {
+    // Gate context items must be bound to this buyer identity to unlock a gate
+    pub buyer_binding: BuyerBinding,
+    // Compatibility switch for gate contexts issued before they were bound to a wallet
+    pub allow_contexts_without_address: bool,
+    // Tolerance applied to `issuedAt` and `expiresAt`. It should cover how often the
+    // app refreshes the reference time metafield, as well as clock differences.
+    pub clock_skew_seconds: i64,
+}
+
+impl Default for FunctionConfiguration {
+    fn default() -> Self {
+        FunctionConfiguration {
+            buyer_binding: BuyerBinding::None,
+            allow_contexts_without_address: false,
+            clock_skew_seconds: 300,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Keyring {
+    pub keys: Vec<SigningKey>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SigningKey {
+    pub kid: Option<String>,
+    pub secret: String,
+    #[serde(default)]
+    pub status: KeyStatus,
+    #[serde(default)]
+    pub derivation: KeyDerivation,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum KeyDerivation {
+    // The secret itself verifies the contexts of every gate
+    #[default]
+    Direct,
+    // Each gate has its own key derived from the secret, see `gate_key`
+    Hkdf,
+}
+
+#[derive(Clone, Debug, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum KeyStatus {
+    // Signs new gate contexts and verifies contexts with or without a `kid`
+    #[default]
+    Active,
+    // Only verifies contexts that name it by `kid`, until they expire from carts
+    Retiring,
+    Revoked,
+}
+
+impl Keyring {
+    fn verification_keys(&self, kid: Option<&str>) -> Vec<&SigningKey> {
+        self.keys
+            .iter()
+            .filter(|key| match kid {
+                Some(kid) => key.kid.as_deref() == Some(kid) && key.status != KeyStatus::Revoked,
+                None => key.status == KeyStatus::Active,
+            })
+            .collect()
+    }
+}
+
+pub struct VerificationContext<'a> {
+    pub keyring: Option<&'a Keyring>,
+    pub public_key: Option<&'a PublicKey>,
+    pub configuration: &'a FunctionConfiguration,
+    pub buyer_identity: Option<&'a input::InputCartBuyerIdentity>,
+    // Unix timestamp in seconds that the app refreshes regularly, functions have no clock
+    pub reference_time: Option<i64>,
+    pub revocation_list: &'a RevocationList,
+}
+
+// Nonces of leaked gate contexts, which unlock no gate even though their signature
+// is valid. The app stores them sorted, so that each lookup is a binary search
+// without sorting the list first.
+#[derive(Clone, Debug, Default)]
+pub struct RevocationList {
+    nonces: Vec<String>,
+}
+
+impl RevocationList {
+    // The binary search could miss a revoked nonce in a list that is not sorted
+    fn new(nonces: Vec<String>) -> std::result::Result<RevocationList, GateError> {
+        if nonces.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(GateError::MalformedMetafield(
+                "revoked_nonces",
+                "nonces are not sorted".to_string(),
+            ));
+        }
+
+        Ok(RevocationList { nonces })
+    }
+
+    fn is_revoked(&self, nonce: &str) -> bool {
+        self.nonces
+            .binary_search_by(|revoked| revoked.as_str().cmp(nonce))
+            .is_ok()
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
+struct GateReactionMetafield {
+    name: String,
+    purchase_limit: StringNumberOrNumber,
+}
+
+#[derive(Clone, Debug)]
 pub struct GateReaction {
     pub name: String,
-    pub purchase_limit: StringNumberOrNumber,
+    pub purchase_limit: i64,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -48,11 +201,11 @@ impl TryInto<i64> for StringNumberOrNumber {
     }
 }
 
-impl ToString for StringNumberOrNumber {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for StringNumberOrNumber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            StringNumberOrNumber::Number(v) => v.to_string(),
-            StringNumberOrNumber::StringNumber(str) => str.clone(),
+            StringNumberOrNumber::Number(v) => write!(f, "{}", v),
+            StringNumberOrNumber::StringNumber(str) => write!(f, "{}", str),
         }
     }
 }
@@ -60,9 +213,59 @@ impl ToString for StringNumberOrNumber {
 #[shopify_function]
 fn function(input: input::ResponseData) -> Result<output::FunctionResult> {
     let cart_lines = input.cart.lines;
-    let gate_context = parse_gate_context_from_cart_attribute(&input.cart.attribute);
+    // A gate context that cannot be read unlocks nothing, like a missing one
+    let gate_context = parse_gate_context_from_cart_attribute(&input.cart.attribute)
+        .unwrap_or_else(|error| {
+            eprintln!("Rejected gate context, no gate can be unlocked: {}", error);
+            vec![]
+        });
     let mut errors = Vec::new();
 
+    // Gate contexts are signed either with a secret key shared between the app and
+    // the function, or with a private key only known to the app. Without any key no
+    // gate context can be trusted, so every gated product stays locked.
+    let keyring = optional_metafield(parse_keyring_from_metafield(
+        input.validation.secret_key.as_ref(),
+    ));
+    let public_key = optional_metafield(parse_public_key_from_metafield(
+        input.validation.public_key.as_ref(),
+    ));
+    if keyring.is_none() && public_key.is_none() {
+        eprintln!(
+            "Missing secret_key and public_key metafields on the validation, no gate can be unlocked"
+        );
+    }
+
+    let configuration = parse_configuration_from_metafield(input.validation.configuration.as_ref());
+
+    // A revocation list that cannot be read could let leaked gate contexts through
+    let revocation_list =
+        parse_revocation_list_from_metafield(input.validation.revoked_nonces.as_ref());
+
+    let verification_context = match (&configuration, &revocation_list) {
+        (Ok(configuration), Ok(revocation_list)) => Some(VerificationContext {
+            keyring: keyring.as_ref(),
+            public_key: public_key.as_ref(),
+            configuration,
+            buyer_identity: input.cart.buyer_identity.as_ref(),
+            reference_time: optional_metafield(parse_reference_time_from_metafield(
+                input.validation.reference_time.as_ref(),
+            )),
+            revocation_list,
+        }),
+        (Err(error), _) | (_, Err(error)) => {
+            eprintln!("No gate can be unlocked: {}", error);
+            None
+        }
+    };
+
+    let unlocked_by_aggregates = match &verification_context {
+        Some(verification_context) => {
+            aggregate_unlocked_gate_configuration_ids(&gate_context, verification_context)
+        }
+        None => HashSet::new(),
+    };
+
     let product_variants = cart_lines.iter().flat_map(|line| {
         // Merchandise is a union type, so we need to match on the variant
         match &line.merchandise {
@@ -78,23 +281,44 @@ fn function(input: input::ResponseData) -> Result<output::FunctionResult> {
                 gate_context_item.id == Some(gate_configuration.id.to_string())
             });
 
-            let gate_unlocked = match &gate_context_item {
-                Some(ctx) => is_signature_valid(ctx, &gate_configuration),
-                _ => false,
-            };
-
-            let gate_reaction: GateReaction =
-                parse_gate_reaction_from_metafield(gate_configuration.metafield.as_ref());
+            let verified_item = gate_context_item.filter(|ctx| match &verification_context {
+                Some(verification_context) => {
+                    is_signature_valid(ctx, &gate_configuration.id, verification_context)
+                }
+                None => false,
+            });
+            let gate_unlocked =
+                unlocked_by_aggregates.contains(&gate_configuration.id) || verified_item.is_some();
 
             if !gate_unlocked {
-                let error_message = format!(
-                    "Drats! You don't have access to this product. It is gated!"
-                );
+                let error_message =
+                    "Drats! You don't have access to this product. It is gated!".to_string();
                 errors.push(FunctionError {
                     localized_message: error_message.to_owned(),
                     target: "cart".to_owned(),
                 });
             } else {
+                // Without a usable reaction there is no purchase limit to enforce, so
+                // the product cannot be bought until the gate is fixed
+                let gate_reaction =
+                    match parse_gate_reaction_from_metafield(gate_configuration.metafield.as_ref())
+                    {
+                        Ok(gate_reaction) => gate_reaction,
+                        Err(error) => {
+                            eprintln!("Blocking {}: {}", gate_configuration.id, error);
+                            errors.push(FunctionError {
+                                localized_message: "Drats! This product isn't available right now."
+                                    .to_owned(),
+                                target: "cart".to_owned(),
+                            });
+                            continue;
+                        }
+                    };
+
+                if let Some(address) = verified_item.and_then(|ctx| ctx.address.as_ref()) {
+                    eprintln!("{} unlocked by wallet {}", gate_configuration.id, address);
+                }
+
                 // Find the cart line that matches the product variant
                 let line = cart_lines
                     .iter()
@@ -106,11 +330,8 @@ fn function(input: input::ResponseData) -> Result<output::FunctionResult> {
                     })
                     .unwrap();
 
-                // Ensure purchase_limit is i64 - this is coming from a metafield and could be a string
-                let purchase_limit: i64 = gate_reaction
-                    .purchase_limit
-                    .try_into()
-                    .expect("Could not convert purchase limit to i64");
+                // The purchase limit has been checked to be an i64 when parsing the reaction
+                let purchase_limit = gate_reaction.purchase_limit;
 
                 // Add error if quantity is over purchase limit
                 if line.quantity > purchase_limit {
@@ -130,50 +351,488 @@ fn function(input: input::ResponseData) -> Result<output::FunctionResult> {
     Ok(output::FunctionResult { errors })
 }
 
+// A cart without the attribute has no gate context, rather than a malformed one
 fn parse_gate_context_from_cart_attribute(
     attribute: &Option<input::InputCartAttribute>,
-) -> Vec<GateContextItem> {
-    attribute
-        .as_ref()
-        .and_then(|a| a.value.as_ref())
-        .map(|value| serde_json::from_str(&value).unwrap_or_default())
-        .unwrap_or_else(|| vec![])
+) -> std::result::Result<Vec<GateContextItem>, GateError> {
+    match attribute.as_ref().and_then(|a| a.value.as_ref()) {
+        Some(value) if !value.trim().is_empty() => parse_gate_context(value),
+        _ => Ok(vec![]),
+    }
+}
+
+// Storefronts used to write a bare array of items, which is version 1 of the
+// format. Later versions wrap the items in an envelope that names its version, so
+// the format can change without breaking storefronts that still write an older one.
+fn parse_gate_context(value: &str) -> std::result::Result<Vec<GateContextItem>, GateError> {
+    if let Some(compact) = value.strip_prefix(compact::PREFIX) {
+        return compact::decode(compact);
+    }
+
+    let gate_context: serde_json::Value = serde_json::from_str(value)
+        .map_err(|error| GateError::MalformedContext(format!("invalid JSON: {}", error)))?;
+
+    match &gate_context {
+        serde_json::Value::Array(_) => parse_gate_context_v1(gate_context),
+        serde_json::Value::Object(envelope) => match envelope.get("v") {
+            Some(serde_json::Value::Number(v)) if v.as_u64() == Some(2) => {
+                parse_gate_context_v2(gate_context)
+            }
+            Some(v) => Err(GateError::UnknownVersion(v.to_string())),
+            None => Err(GateError::MalformedContext(
+                "envelope without a version".to_string(),
+            )),
+        },
+        _ => Err(GateError::MalformedContext(
+            "neither an array of items nor an envelope".to_string(),
+        )),
+    }
+}
+
+fn parse_gate_context_v1(
+    gate_context: serde_json::Value,
+) -> std::result::Result<Vec<GateContextItem>, GateError> {
+    serde_json::from_value(gate_context)
+        .map_err(|error| GateError::MalformedContext(format!("invalid items: {}", error)))
 }
 
+#[derive(Deserialize)]
+struct GateContextEnvelopeV2 {
+    items: Vec<GateContextItem>,
+}
+
+fn parse_gate_context_v2(
+    gate_context: serde_json::Value,
+) -> std::result::Result<Vec<GateContextItem>, GateError> {
+    serde_json::from_value::<GateContextEnvelopeV2>(gate_context)
+        .map(|envelope| envelope.items)
+        .map_err(|error| {
+            GateError::MalformedContext(format!("invalid version 2 envelope: {}", error))
+        })
+}
+
+// Metafields that the function can do without are only logged when malformed
+fn optional_metafield<T>(result: std::result::Result<T, GateError>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(GateError::MissingMetafield(_)) => None,
+        Err(error) => {
+            eprintln!("Ignoring {}", error);
+            None
+        }
+    }
+}
+
+fn parse_keyring_from_metafield(
+    metafield: Option<&input::InputValidationSecretKey>,
+) -> std::result::Result<Keyring, GateError> {
+    let value = match metafield.map(|metafield| metafield.value.trim()) {
+        Some(value) if !value.is_empty() => value,
+        _ => return Err(GateError::MissingMetafield("secret_key")),
+    };
+
+    // A bare secret is a keyring with a single active key and no `kid`
+    let keyring = if value.starts_with('{') {
+        serde_json::from_str(value)
+            .map_err(|error| GateError::MalformedMetafield("secret_key", error.to_string()))?
+    } else {
+        Keyring {
+            keys: vec![SigningKey {
+                kid: None,
+                secret: value.to_string(),
+                status: KeyStatus::Active,
+                derivation: KeyDerivation::Direct,
+            }],
+        }
+    };
+
+    let has_usable_key = keyring
+        .keys
+        .iter()
+        .any(|key| key.status != KeyStatus::Revoked && !key.secret.is_empty());
+    if has_usable_key {
+        Ok(keyring)
+    } else {
+        Err(GateError::MalformedMetafield(
+            "secret_key",
+            "no usable key".to_string(),
+        ))
+    }
+}
+
+fn parse_public_key_from_metafield(
+    metafield: Option<&input::InputValidationPublicKey>,
+) -> std::result::Result<PublicKey, GateError> {
+    let value = metafield
+        .map(|metafield| metafield.value.trim())
+        .ok_or(GateError::MissingMetafield("public_key"))?;
+    let bytes = decode_hex("public_key", value)?;
+
+    PublicKey::from_slice(&bytes).map_err(|_| {
+        GateError::MalformedMetafield("public_key", "not an Ed25519 public key".to_string())
+    })
+}
+
+// A missing metafield is the default configuration
+fn parse_configuration_from_metafield(
+    metafield: Option<&input::InputValidationConfiguration>,
+) -> std::result::Result<FunctionConfiguration, GateError> {
+    match metafield {
+        Some(metafield) => serde_json::from_str(metafield.value.as_str())
+            .map_err(|error| GateError::MalformedMetafield("configuration", error.to_string())),
+        None => Ok(FunctionConfiguration::default()),
+    }
+}
+
+// A missing metafield is an empty revocation list
+fn parse_revocation_list_from_metafield(
+    metafield: Option<&input::InputValidationRevokedNonces>,
+) -> std::result::Result<RevocationList, GateError> {
+    match metafield {
+        Some(metafield) => serde_json::from_str(&metafield.value)
+            .map_err(|error| GateError::MalformedMetafield("revoked_nonces", error.to_string()))
+            .and_then(RevocationList::new),
+        None => Ok(RevocationList::default()),
+    }
+}
+
+fn parse_reference_time_from_metafield(
+    metafield: Option<&input::InputValidationReferenceTime>,
+) -> std::result::Result<i64, GateError> {
+    let metafield = metafield.ok_or(GateError::MissingMetafield("reference_time"))?;
+    metafield
+        .value
+        .trim()
+        .parse()
+        .map_err(|error: std::num::ParseIntError| {
+            GateError::MalformedMetafield("reference_time", error.to_string())
+        })
+}
+
+// The purchase limit is stored as a number or a string, and comes out as an i64
 fn parse_gate_reaction_from_metafield(
     metafield: Option<
         &input::InputCartLinesMerchandiseOnProductVariantProductGatesConfigurationMetafield,
     >,
-) -> GateReaction {
-    metafield
-        .map(|metafield| serde_json::from_str(metafield.value.as_str()).unwrap())
-        .unwrap()
+) -> std::result::Result<GateReaction, GateError> {
+    let metafield = metafield.ok_or(GateError::MissingMetafield("reaction"))?;
+    let gate_reaction: GateReactionMetafield = serde_json::from_str(metafield.value.as_str())
+        .map_err(|error| GateError::InvalidReaction(error.to_string()))?;
+    let purchase_limit = gate_reaction
+        .purchase_limit
+        .clone()
+        .try_into()
+        .map_err(|_| {
+            GateError::InvalidReaction(format!(
+                "purchase limit {} is not an integer",
+                gate_reaction.purchase_limit
+            ))
+        })?;
+
+    Ok(GateReaction {
+        name: gate_reaction.name,
+        purchase_limit,
+    })
+}
+
+fn decode_hex(field: &'static str, value: &str) -> std::result::Result<Vec<u8>, GateError> {
+    hex::decode(value).map_err(|_| GateError::BadHex(field))
+}
+
+// Aggregate items sign many gate configurations at once, so they are verified once
+// per execution rather than once per gated product
+fn aggregate_unlocked_gate_configuration_ids(
+    gate_context: &[GateContextItem],
+    verification_context: &VerificationContext,
+) -> HashSet<ID> {
+    let mut unlocked = HashSet::new();
+    for gate_context_item in gate_context {
+        let ids = match &gate_context_item.ids {
+            Some(ids) => ids,
+            None => continue,
+        };
+
+        if is_signature_valid(gate_context_item, "", verification_context) {
+            if let Some(address) = &gate_context_item.address {
+                eprintln!("{} unlocked by wallet {}", ids.join(", "), address);
+            }
+            unlocked.extend(ids.iter().cloned());
+        }
+    }
+
+    unlocked
 }
 
 fn is_signature_valid(
     gate_context_item: &GateContextItem,
-    gate_configuration: &input::InputCartLinesMerchandiseOnProductVariantProductGatesConfiguration,
+    gate_configuration_id: &str,
+    verification_context: &VerificationContext,
+) -> bool {
+    // An item is either for a single gate configuration or an aggregate. Token
+    // counts are per gate, so aggregates cannot carry one.
+    if gate_context_item.ids.is_some()
+        && (gate_context_item.id.is_some() || gate_context_item.token_count.is_some())
+    {
+        return false;
+    }
+
+    let required_binding = verification_context.configuration.buyer_binding;
+    if required_binding != BuyerBinding::None && gate_context_item.binding != Some(required_binding)
+    {
+        return false;
+    }
+
+    if gate_context_item.address.is_none()
+        && !verification_context
+            .configuration
+            .allow_contexts_without_address
+    {
+        return false;
+    }
+
+    if !is_within_validity_period(gate_context_item, verification_context) {
+        return false;
+    }
+
+    if let Some(nonce) = &gate_context_item.nonce {
+        if verification_context.revocation_list.is_revoked(nonce) {
+            eprintln!("Gate context with nonce {} is revoked", nonce);
+            return false;
+        }
+    }
+
+    let message = match signed_message(
+        gate_context_item,
+        gate_configuration_id,
+        verification_context.buyer_identity,
+    ) {
+        Some(message) => message,
+        None => return false,
+    };
+
+    // Each item is signed with exactly one scheme
+    match (&gate_context_item.hmac, &gate_context_item.ed25519) {
+        (Some(hmac), None) => match verification_context.keyring {
+            Some(keyring) => is_hmac_valid(
+                hmac,
+                &message,
+                gate_configuration_id,
+                gate_context_item,
+                keyring,
+            ),
+            None => false,
+        },
+        (None, Some(signature)) => match verification_context.public_key {
+            Some(public_key) => is_ed25519_signature_valid(signature, &message, public_key),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+fn is_hmac_valid(
+    hmac: &str,
+    message: &str,
+    gate_configuration_id: &str,
+    gate_context_item: &GateContextItem,
+    keyring: &Keyring,
+) -> bool {
+    // Compare raw bytes rather than hex strings, so the comparison is constant-time
+    // and does not depend on the case of the hex digits
+    let signature = match decode_hex("hmac", hmac) {
+        Ok(signature) => signature,
+        Err(error) => {
+            eprintln!("Rejected gate context item: {}", error);
+            return false;
+        }
+    };
+
+    keyring
+        .verification_keys(gate_context_item.kid.as_deref())
+        .into_iter()
+        .any(|key| {
+            let mac = match key.derivation {
+                KeyDerivation::Direct => hmac_signature(key.secret.as_bytes(), message),
+                // Aggregate items span gates, so no single gate key can verify them
+                KeyDerivation::Hkdf if gate_context_item.ids.is_some() => return false,
+                KeyDerivation::Hkdf => {
+                    hmac_signature(&gate_key(&key.secret, gate_configuration_id), message)
+                }
+            };
+            if gate_context_item.hmac_truncated {
+                signature.len() == compact::TRUNCATED_HMAC_LENGTH
+                    && bool::from(
+                        mac.finalize().into_bytes()[..compact::TRUNCATED_HMAC_LENGTH]
+                            .ct_eq(&signature),
+                    )
+            } else {
+                mac.verify(&signature).is_ok()
+            }
+        })
+}
+
+fn is_ed25519_signature_valid(signature: &str, message: &str, public_key: &PublicKey) -> bool {
+    let bytes = match decode_hex("ed25519", signature) {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            eprintln!("Rejected gate context item: {}", error);
+            return false;
+        }
+    };
+    let signature = match Signature::from_slice(&bytes) {
+        Ok(signature) => signature,
+        Err(_) => {
+            eprintln!("Rejected gate context item: ed25519 is not a signature");
+            return false;
+        }
+    };
+
+    public_key.verify(message, &signature).is_ok()
+}
+
+// Version 1 messages are the bare gate configuration ID. Version 2 messages are
+// `key=value` lines after a `v2` header, so that more signed fields can be added
+// without two different items ever producing the same message. Aggregate items
+// sign the sorted and deduplicated gate configuration IDs as one `ids=` line
+// instead of the `id=` line. Returns `None` when the item cannot be verified
+// against this cart, e.g. it is bound to a customer and the buyer is not logged in.
+fn signed_message(
+    gate_context_item: &GateContextItem,
+    gate_configuration_id: &str,
+    buyer_identity: Option<&input::InputCartBuyerIdentity>,
+) -> Option<String> {
+    let binding = gate_context_item.binding.unwrap_or_default();
+
+    match gate_context_item.v.unwrap_or(1) {
+        1 if binding == BuyerBinding::None
+            && gate_context_item.ids.is_none()
+            && gate_context_item.nonce.is_none()
+            && gate_context_item.token_count.is_none()
+            && gate_context_item.address.is_none()
+            && gate_context_item.issued_at.is_none()
+            && gate_context_item.expires_at.is_none() =>
+        {
+            Some(gate_configuration_id.to_string())
+        }
+        2 => {
+            let subject = match &gate_context_item.ids {
+                Some(ids) => {
+                    let mut ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+                    ids.sort_unstable();
+                    ids.dedup();
+                    format!("ids={}", ids.join(","))
+                }
+                None => format!("id={}", gate_configuration_id),
+            };
+            let mut lines = vec!["v2".to_string(), subject];
+            if let Some(address) = &gate_context_item.address {
+                lines.push(format!("address={}", normalized_wallet_address(address)?));
+            }
+            if let Some(issued_at) = gate_context_item.issued_at {
+                lines.push(format!("issuedAt={}", issued_at));
+            }
+            if let Some(expires_at) = gate_context_item.expires_at {
+                lines.push(format!("expiresAt={}", expires_at));
+            }
+            if let Some(nonce) = &gate_context_item.nonce {
+                lines.push(format!("nonce={}", valid_nonce(nonce)?));
+            }
+            if let Some(token_count) = gate_context_item.token_count {
+                lines.push(format!("tokenCount={}", token_count));
+            }
+            match binding {
+                BuyerBinding::None => {}
+                BuyerBinding::Customer => {
+                    let customer = buyer_identity?.customer.as_ref()?;
+                    lines.push(format!("customer={}", customer.id));
+                }
+                BuyerBinding::Email => {
+                    // Only ASCII letters are lowercased, like the discount function does
+                    let email = buyer_identity?.email.as_ref()?.trim().to_ascii_lowercase();
+                    lines.push(format!("email={}", email));
+                }
+            }
+            Some(lines.join("\n"))
+        }
+        _ => None,
+    }
+}
+
+// Expired items are locked. Items that expire cannot be checked without a
+// reference time, so they are locked as well when it is missing.
+fn is_within_validity_period(
+    gate_context_item: &GateContextItem,
+    verification_context: &VerificationContext,
 ) -> bool {
-    let hmac = match &gate_context_item.hmac {
-        Some(hmac) => hmac,
-        _ => return false,
+    if gate_context_item.issued_at.is_none() && gate_context_item.expires_at.is_none() {
+        return true;
+    }
+
+    let reference_time = match verification_context.reference_time {
+        Some(reference_time) => reference_time,
+        None => return false,
     };
+    let clock_skew = verification_context.configuration.clock_skew_seconds;
 
-    let message = &gate_configuration.id;
-    let signature = hmac_signature(SECRET_KEY, message);
+    if let Some(issued_at) = gate_context_item.issued_at {
+        if issued_at > reference_time.saturating_add(clock_skew) {
+            return false;
+        }
+    }
+    if let Some(expires_at) = gate_context_item.expires_at {
+        if expires_at.saturating_add(clock_skew) < reference_time {
+            return false;
+        }
+    }
+
+    true
+}
+
+// Nonces are signed as a line of their own, so they cannot contain a line break or
+// anything else that could pass for another signed field
+fn valid_nonce(nonce: &str) -> Option<&str> {
+    let valid = !nonce.is_empty()
+        && nonce.len() <= 64
+        && nonce
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    valid.then_some(nonce)
+}
 
-    &signature == hmac
+// Checksummed and lowercase addresses refer to the same wallet, so addresses are
+// signed lowercase
+fn normalized_wallet_address(address: &str) -> Option<String> {
+    let hex_digits = address.strip_prefix("0x")?;
+    if hex_digits.len() == 40 && hex_digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(address.to_ascii_lowercase())
+    } else {
+        None
+    }
 }
 
-fn hmac_signature(key: &str, msg: &str) -> String {
-    type HmacSha256 = Hmac<Sha256>;
+type HmacSha256 = Hmac<Sha256>;
+
+// Salt for deriving gate keys, which must never change for as long as keys derived
+// with it are in use
+const GATE_KEY_SALT: &[u8] = b"tokengating-example-app/gate-key";
 
-    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).unwrap();
-    mac.update(&msg.as_bytes());
+// A key that only verifies the contexts of one gate configuration, so that a
+// leaked gate key cannot mint contexts for any other gate
+fn gate_key(secret: &str, gate_configuration_id: &str) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(GATE_KEY_SALT), secret.as_bytes())
+        .expand(gate_configuration_id.as_bytes(), &mut key)
+        .unwrap();
+
+    key
+}
 
-    let code_bytes = mac.finalize().into_bytes();
+fn hmac_signature(key: &[u8], msg: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(msg.as_bytes());
 
-    return hex::encode(&code_bytes.to_vec());
+    mac
 }
 
 #[cfg(test)]
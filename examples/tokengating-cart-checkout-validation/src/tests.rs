@@ -35,16 +35,23 @@ fn test_errors_without_valid_gate_context() -> Result<()> {
                             }
                         }
                     ]
+                },
+                "validation": {
+                    "secretKey": {
+                        "value": "secret-key"
+                    },
+                    "configuration": {
+                        "value": "{\"allowContextsWithoutAddress\": true}"
+                    }
                 }
             }
         "#,
     )?;
-    let mut errors = Vec::new();
-    errors.push(FunctionError {
+    let errors = vec![FunctionError {
         localized_message: "Drats! You don't have access to this product. It is gated!".to_owned(),
         target: "cart".to_owned(),
-    });
-    let expected = crate::output::FunctionResult { errors: errors };
+    }];
+    let expected = crate::output::FunctionResult { errors };
 
     assert_eq!(result, expected);
     Ok(())
@@ -84,16 +91,23 @@ fn test_errors_with_quantity_over_limit() -> Result<()> {
                             }
                         }
                     ]
+                },
+                "validation": {
+                    "secretKey": {
+                        "value": "secret-key"
+                    },
+                    "configuration": {
+                        "value": "{\"allowContextsWithoutAddress\": true}"
+                    }
                 }
             }
         "#,
     )?;
-    let mut errors = Vec::new();
-    errors.push(FunctionError {
+    let errors = vec![FunctionError {
         localized_message: "Drats! You can only order 2 with your Snowdevil token!".to_owned(),
         target: "cart".to_owned(),
-    });
-    let expected = crate::output::FunctionResult { errors: errors };
+    }];
+    let expected = crate::output::FunctionResult { errors };
 
     assert_eq!(result, expected);
     Ok(())
@@ -133,13 +147,884 @@ fn test_no_errors_valid_gate_context() -> Result<()> {
                             }
                         }
                     ]
+                },
+                "validation": {
+                    "secretKey": {
+                        "value": "secret-key"
+                    },
+                    "configuration": {
+                        "value": "{\"allowContextsWithoutAddress\": true}"
+                    }
+                }
+            }
+        "#,
+    )?;
+    let errors = Vec::new();
+    let expected = crate::output::FunctionResult { errors };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_missing_secret_key() -> Result<()> {
+    let result = run_function_with_input(
+        function,
+        r#"
+            {
+                "cart": {
+                    "attribute": {
+                        "value": "[{\"id\": \"gid://shopify/GateConfiguration/1\", \"hmac\": \"bd3862797c3e532f9f07e6672192d46792ee3591a0c7fe279e14d971eb541b37\"}]"
+                    },
+                    "lines": [
+                        {
+                            "quantity": 1,
+                            "merchandise": {
+                                "__typename": "ProductVariant",
+                                "id": "gid://shopify/ProductVariant/1",
+                                "product": {
+                                    "id": "gid://shopify/Product/1",
+                                    "gates": [
+                                        {
+                                            "id": "gid://shopify/GateSubject/1",
+                                            "configuration": {
+                                                "id": "gid://shopify/GateConfiguration/1",
+                                                "handle": "tokengating-example-app",
+                                                "metafield": {
+                                                    "value": "{\"name\":\"Snowdevil exclusive\",\"type\":\"exclusive_access\",\"purchase_limit\": \"2\"}"
+                                                }
+                                            }
+                                        }
+                                    ]
+                                }
+                            }
+                        }
+                    ]
+                },
+                "validation": {
+                    "secretKey": null
+                }
+            }
+        "#,
+    )?;
+    let errors = vec![FunctionError {
+        localized_message: "Drats! You don't have access to this product. It is gated!".to_owned(),
+        target: "cart".to_owned(),
+    }];
+    let expected = crate::output::FunctionResult { errors };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_no_errors_with_gate_context_signed_by_retiring_key() -> Result<()> {
+    let result = run_function_with_input(
+        function,
+        r#"
+            {
+                "cart": {
+                    "attribute": {
+                        "value": "[{\"id\": \"gid://shopify/GateConfiguration/1\", \"hmac\": \"7dad4c33b5a433b114bc403855e54f7edd663b94c00ff9be074b963a51be56f3\", \"kid\": \"2023-04\"}]"
+                    },
+                    "lines": [
+                        {
+                            "quantity": 1,
+                            "merchandise": {
+                                "__typename": "ProductVariant",
+                                "id": "gid://shopify/ProductVariant/1",
+                                "product": {
+                                    "id": "gid://shopify/Product/1",
+                                    "gates": [
+                                        {
+                                            "id": "gid://shopify/GateSubject/1",
+                                            "configuration": {
+                                                "id": "gid://shopify/GateConfiguration/1",
+                                                "handle": "tokengating-example-app",
+                                                "metafield": {
+                                                    "value": "{\"name\":\"Snowdevil exclusive\",\"type\":\"exclusive_access\",\"purchase_limit\": \"2\"}"
+                                                }
+                                            }
+                                        }
+                                    ]
+                                }
+                            }
+                        }
+                    ]
+                },
+                "validation": {
+                    "secretKey": {
+                        "value": "{\"keys\": [{\"kid\": \"2023-10\", \"secret\": \"new-secret-key\"}, {\"kid\": \"2023-04\", \"secret\": \"old-secret-key\", \"status\": \"retiring\"}]}"
+                    },
+                    "configuration": {
+                        "value": "{\"allowContextsWithoutAddress\": true}"
+                    }
                 }
             }
         "#,
     )?;
     let errors = Vec::new();
-    let expected = crate::output::FunctionResult { errors: errors };
+    let expected = crate::output::FunctionResult { errors };
 
     assert_eq!(result, expected);
     Ok(())
 }
+
+#[test]
+fn test_errors_with_gate_context_bound_to_another_buyer() -> Result<()> {
+    let result = run_function_with_input(
+        function,
+        r#"
+            {
+                "cart": {
+                    "buyerIdentity": {
+                        "customer": {
+                            "id": "gid://shopify/Customer/2"
+                        }
+                    },
+                    "attribute": {
+                        "value": "[{\"id\": \"gid://shopify/GateConfiguration/1\", \"hmac\": \"e0c3f8b0e7d254f0ac2b3e5ba3a46f504013e99cc4c5dba79185effb9ab0cfe5\", \"v\": 2, \"binding\": \"customer\"}]"
+                    },
+                    "lines": [
+                        {
+                            "quantity": 1,
+                            "merchandise": {
+                                "__typename": "ProductVariant",
+                                "id": "gid://shopify/ProductVariant/1",
+                                "product": {
+                                    "id": "gid://shopify/Product/1",
+                                    "gates": [
+                                        {
+                                            "id": "gid://shopify/GateSubject/1",
+                                            "configuration": {
+                                                "id": "gid://shopify/GateConfiguration/1",
+                                                "handle": "tokengating-example-app",
+                                                "metafield": {
+                                                    "value": "{\"name\":\"Snowdevil exclusive\",\"type\":\"exclusive_access\",\"purchase_limit\": \"2\"}"
+                                                }
+                                            }
+                                        }
+                                    ]
+                                }
+                            }
+                        }
+                    ]
+                },
+                "validation": {
+                    "secretKey": {
+                        "value": "secret-key"
+                    },
+                    "configuration": {
+                        "value": "{\"buyerBinding\": \"customer\", \"allowContextsWithoutAddress\": true}"
+                    }
+                }
+            }
+        "#,
+    )?;
+    let errors = vec![FunctionError {
+        localized_message: "Drats! You don't have access to this product. It is gated!".to_owned(),
+        target: "cart".to_owned(),
+    }];
+    let expected = crate::output::FunctionResult { errors };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_no_errors_with_gate_context_bound_to_wallet() -> Result<()> {
+    let result = run_function_with_input(
+        function,
+        r#"
+            {
+                "cart": {
+                    "attribute": {
+                        "value": "[{\"id\": \"gid://shopify/GateConfiguration/1\", \"hmac\": \"73764ac9fd170d65c73ae52c26170107c2d17e24498189a8720cd31a5964d649\", \"v\": 2, \"address\": \"0x71c7656ec7ab88b098defb751b7401b5f6d8976f\"}]"
+                    },
+                    "lines": [
+                        {
+                            "quantity": 1,
+                            "merchandise": {
+                                "__typename": "ProductVariant",
+                                "id": "gid://shopify/ProductVariant/1",
+                                "product": {
+                                    "id": "gid://shopify/Product/1",
+                                    "gates": [
+                                        {
+                                            "id": "gid://shopify/GateSubject/1",
+                                            "configuration": {
+                                                "id": "gid://shopify/GateConfiguration/1",
+                                                "handle": "tokengating-example-app",
+                                                "metafield": {
+                                                    "value": "{\"name\":\"Snowdevil exclusive\",\"type\":\"exclusive_access\",\"purchase_limit\": \"2\"}"
+                                                }
+                                            }
+                                        }
+                                    ]
+                                }
+                            }
+                        }
+                    ]
+                },
+                "validation": {
+                    "secretKey": {
+                        "value": "secret-key"
+                    },
+                    "configuration": {
+                        "value": "{}"
+                    }
+                }
+            }
+        "#,
+    )?;
+    let errors = Vec::new();
+    let expected = crate::output::FunctionResult { errors };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_no_errors_with_version_2_gate_context_envelope() -> Result<()> {
+    let result = run_function_with_input(
+        function,
+        r#"
+            {
+                "cart": {
+                    "attribute": {
+                        "value": "{\"v\": 2, \"items\": [{\"id\": \"gid://shopify/GateConfiguration/1\", \"hmac\": \"bd3862797c3e532f9f07e6672192d46792ee3591a0c7fe279e14d971eb541b37\"}]}"
+                    },
+                    "lines": [
+                        {
+                            "quantity": 1,
+                            "merchandise": {
+                                "__typename": "ProductVariant",
+                                "id": "gid://shopify/ProductVariant/1",
+                                "product": {
+                                    "id": "gid://shopify/Product/1",
+                                    "gates": [
+                                        {
+                                            "id": "gid://shopify/GateSubject/1",
+                                            "configuration": {
+                                                "id": "gid://shopify/GateConfiguration/1",
+                                                "handle": "tokengating-example-app",
+                                                "metafield": {
+                                                    "value": "{\"name\":\"Snowdevil exclusive\",\"type\":\"exclusive_access\",\"purchase_limit\": \"2\"}"
+                                                }
+                                            }
+                                        }
+                                    ]
+                                }
+                            }
+                        }
+                    ]
+                },
+                "validation": {
+                    "secretKey": {
+                        "value": "secret-key"
+                    },
+                    "configuration": {
+                        "value": "{\"allowContextsWithoutAddress\": true}"
+                    }
+                }
+            }
+        "#,
+    )?;
+    let errors = Vec::new();
+    let expected = crate::output::FunctionResult { errors };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_no_errors_with_gate_context_signed_by_derived_gate_key() -> Result<()> {
+    let result = run_function_with_input(
+        function,
+        r#"
+            {
+                "cart": {
+                    "attribute": {
+                        "value": "[{\"id\": \"gid://shopify/GateConfiguration/1\", \"hmac\": \"e0140d80cbe36981da856ca0acedb1f42e54a136fe1a462e8fff356512bd2ae9\"}]"
+                    },
+                    "lines": [
+                        {
+                            "quantity": 1,
+                            "merchandise": {
+                                "__typename": "ProductVariant",
+                                "id": "gid://shopify/ProductVariant/1",
+                                "product": {
+                                    "id": "gid://shopify/Product/1",
+                                    "gates": [
+                                        {
+                                            "id": "gid://shopify/GateSubject/1",
+                                            "configuration": {
+                                                "id": "gid://shopify/GateConfiguration/1",
+                                                "handle": "tokengating-example-app",
+                                                "metafield": {
+                                                    "value": "{\"name\":\"Snowdevil exclusive\",\"type\":\"exclusive_access\",\"purchase_limit\": \"2\"}"
+                                                }
+                                            }
+                                        }
+                                    ]
+                                }
+                            }
+                        }
+                    ]
+                },
+                "validation": {
+                    "secretKey": {
+                        "value": "{\"keys\": [{\"secret\": \"secret-key\", \"derivation\": \"hkdf\"}]}"
+                    },
+                    "configuration": {
+                        "value": "{\"allowContextsWithoutAddress\": true}"
+                    }
+                }
+            }
+        "#,
+    )?;
+    let errors = Vec::new();
+    let expected = crate::output::FunctionResult { errors };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_revoked_gate_context() -> Result<()> {
+    let result = run_function_with_input(
+        function,
+        r#"
+            {
+                "cart": {
+                    "attribute": {
+                        "value": "[{\"id\": \"gid://shopify/GateConfiguration/1\", \"hmac\": \"934781cb8d1179b29945c46f2a2d7795acc1211b20a9677cbc5ecc8f08bdb36e\", \"v\": 2, \"nonce\": \"6f1c2a9b0e7d4c35a8b1f0e2d3c4b5a6\"}]"
+                    },
+                    "lines": [
+                        {
+                            "quantity": 1,
+                            "merchandise": {
+                                "__typename": "ProductVariant",
+                                "id": "gid://shopify/ProductVariant/1",
+                                "product": {
+                                    "id": "gid://shopify/Product/1",
+                                    "gates": [
+                                        {
+                                            "id": "gid://shopify/GateSubject/1",
+                                            "configuration": {
+                                                "id": "gid://shopify/GateConfiguration/1",
+                                                "handle": "tokengating-example-app",
+                                                "metafield": {
+                                                    "value": "{\"name\":\"Snowdevil exclusive\",\"type\":\"exclusive_access\",\"purchase_limit\": \"2\"}"
+                                                }
+                                            }
+                                        }
+                                    ]
+                                }
+                            }
+                        }
+                    ]
+                },
+                "validation": {
+                    "secretKey": {
+                        "value": "secret-key"
+                    },
+                    "configuration": {
+                        "value": "{\"allowContextsWithoutAddress\": true}"
+                    },
+                    "revokedNonces": {
+                        "value": "[\"6f1c2a9b0e7d4c35a8b1f0e2d3c4b5a6\"]"
+                    }
+                }
+            }
+        "#,
+    )?;
+    let errors = vec![FunctionError {
+        localized_message: "Drats! You don't have access to this product. It is gated!".to_owned(),
+        target: "cart".to_owned(),
+    }];
+    let expected = crate::output::FunctionResult { errors };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_invalid_purchase_limit() -> Result<()> {
+    let result = run_function_with_input(
+        function,
+        r#"
+            {
+                "cart": {
+                    "attribute": {
+                        "value": "[{\"id\": \"gid://shopify/GateConfiguration/1\", \"hmac\": \"bd3862797c3e532f9f07e6672192d46792ee3591a0c7fe279e14d971eb541b37\"}]"
+                    },
+                    "lines": [
+                        {
+                            "quantity": 1,
+                            "merchandise": {
+                                "__typename": "ProductVariant",
+                                "id": "gid://shopify/ProductVariant/1",
+                                "product": {
+                                    "id": "gid://shopify/Product/1",
+                                    "gates": [
+                                        {
+                                            "id": "gid://shopify/GateSubject/1",
+                                            "configuration": {
+                                                "id": "gid://shopify/GateConfiguration/1",
+                                                "handle": "tokengating-example-app",
+                                                "metafield": {
+                                                    "value": "{\"name\":\"Snowdevil exclusive\",\"type\":\"exclusive_access\",\"purchase_limit\": \"two\"}"
+                                                }
+                                            }
+                                        }
+                                    ]
+                                }
+                            }
+                        }
+                    ]
+                },
+                "validation": {
+                    "secretKey": {
+                        "value": "secret-key"
+                    },
+                    "configuration": {
+                        "value": "{\"allowContextsWithoutAddress\": true}"
+                    }
+                }
+            }
+        "#,
+    )?;
+    let errors = vec![FunctionError {
+        localized_message: "Drats! This product isn't available right now.".to_owned(),
+        target: "cart".to_owned(),
+    }];
+    let expected = crate::output::FunctionResult { errors };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+// The cart of the tests above, with the gate context and validation metafields
+// given as JSON. A string is the attribute value itself, e.g. a compact gate context.
+fn run_with_gate_context(
+    gate_context: serde_json::Value,
+    validation: serde_json::Value,
+) -> Result<crate::output::FunctionResult> {
+    let attribute_value = match gate_context {
+        serde_json::Value::String(value) => value,
+        gate_context => gate_context.to_string(),
+    };
+    let input = serde_json::json!({
+        "cart": {
+            "attribute": {
+                "value": attribute_value
+            },
+            "lines": [
+                {
+                    "quantity": 1,
+                    "merchandise": {
+                        "__typename": "ProductVariant",
+                        "id": "gid://shopify/ProductVariant/1",
+                        "product": {
+                            "id": "gid://shopify/Product/1",
+                            "gates": [
+                                {
+                                    "id": "gid://shopify/GateSubject/1",
+                                    "configuration": {
+                                        "id": "gid://shopify/GateConfiguration/1",
+                                        "handle": "tokengating-example-app",
+                                        "metafield": {
+                                            "value": "{\"name\":\"Snowdevil exclusive\",\"type\":\"exclusive_access\",\"purchase_limit\": \"2\"}"
+                                        }
+                                    }
+                                }
+                            ]
+                        }
+                    }
+                }
+            ]
+        },
+        "validation": validation
+    });
+
+    run_function_with_input(function, &input.to_string())
+}
+
+fn secret_key_validation() -> serde_json::Value {
+    serde_json::json!({
+        "secretKey": {
+            "value": "secret-key"
+        },
+        "configuration": {
+            "value": "{\"allowContextsWithoutAddress\": true}"
+        }
+    })
+}
+
+fn gate_context_with_hmac(hmac: &str) -> serde_json::Value {
+    serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": hmac
+    }])
+}
+
+fn no_errors() -> crate::output::FunctionResult {
+    crate::output::FunctionResult { errors: vec![] }
+}
+
+fn gated() -> crate::output::FunctionResult {
+    crate::output::FunctionResult {
+        errors: vec![FunctionError {
+            localized_message: "Drats! You don't have access to this product. It is gated!"
+                .to_owned(),
+            target: "cart".to_owned(),
+        }],
+    }
+}
+
+#[test]
+fn test_no_errors_with_uppercase_hmac_in_gate_context() -> Result<()> {
+    let gate_context =
+        gate_context_with_hmac("BD3862797C3E532F9F07E6672192D46792EE3591A0C7FE279E14D971EB541B37");
+
+    let result = run_with_gate_context(gate_context, secret_key_validation())?;
+    assert_eq!(result, no_errors());
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_odd_length_hmac_in_gate_context() -> Result<()> {
+    let gate_context =
+        gate_context_with_hmac("bd3862797c3e532f9f07e6672192d46792ee3591a0c7fe279e14d971eb541b3");
+
+    let result = run_with_gate_context(gate_context, secret_key_validation())?;
+    assert_eq!(result, gated());
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_non_hex_hmac_in_gate_context() -> Result<()> {
+    let gate_context =
+        gate_context_with_hmac("zz3862797c3e532f9f07e6672192d46792ee3591a0c7fe279e14d971eb541b37");
+
+    let result = run_with_gate_context(gate_context, secret_key_validation())?;
+    assert_eq!(result, gated());
+    Ok(())
+}
+
+fn expiring_gate_context() -> serde_json::Value {
+    serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "19a02e3d00ba71e0cbd8035efa14f766ad439ae2c7ad69733db5fe74874bce47",
+        "v": 2,
+        "issuedAt": 1700000000,
+        "expiresAt": 1700003600
+    }])
+}
+
+fn validation_at(reference_time: &str) -> serde_json::Value {
+    let mut validation = secret_key_validation();
+    validation["referenceTime"] = serde_json::json!({ "value": reference_time });
+
+    validation
+}
+
+#[test]
+fn test_no_errors_with_unexpired_gate_context() -> Result<()> {
+    let result = run_with_gate_context(expiring_gate_context(), validation_at("1700001800"))?;
+    assert_eq!(result, no_errors());
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_expired_gate_context() -> Result<()> {
+    let result = run_with_gate_context(expiring_gate_context(), validation_at("1700003901"))?;
+    assert_eq!(result, gated());
+    Ok(())
+}
+
+#[test]
+fn test_no_errors_with_expired_gate_context_within_clock_skew() -> Result<()> {
+    let result = run_with_gate_context(expiring_gate_context(), validation_at("1700003900"))?;
+    assert_eq!(result, no_errors());
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_gate_context_issued_after_reference_time() -> Result<()> {
+    let within_clock_skew =
+        run_with_gate_context(expiring_gate_context(), validation_at("1699999700"))?;
+    assert_eq!(within_clock_skew, no_errors());
+
+    let beyond_clock_skew =
+        run_with_gate_context(expiring_gate_context(), validation_at("1699999699"))?;
+    assert_eq!(beyond_clock_skew, gated());
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_expiring_gate_context_and_no_reference_time() -> Result<()> {
+    let result = run_with_gate_context(expiring_gate_context(), secret_key_validation())?;
+    assert_eq!(result, gated());
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_tampered_expiry_in_gate_context() -> Result<()> {
+    let mut gate_context = expiring_gate_context();
+    gate_context[0]["expiresAt"] = serde_json::json!(1800000000);
+
+    let result = run_with_gate_context(gate_context, validation_at("1700001800"))?;
+    assert_eq!(result, gated());
+    Ok(())
+}
+
+// Ed25519 key pair from the seed [7; 32], and its signature of the version 1
+// message of gate configuration 1
+const ED25519_PUBLIC_KEY: &str = "ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c";
+const ED25519_SIGNATURE: &str = "19f036a269abc1992c6ba15ae7c35625407f7e4118726792b120bbfe879b613e1bb726ee7ae19c9dc5dc9cc2ae71e01ac570df1c8b4ca642a54de4c8f3c8e506";
+// The public key from the seed [8; 32]
+const OTHER_ED25519_PUBLIC_KEY: &str =
+    "1398f62c6d1a457c51ba6a4b5f3dbd2f69fca93216218dc8997e416bd17d93ca";
+
+fn ed25519_gate_context() -> serde_json::Value {
+    serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "ed25519": ED25519_SIGNATURE
+    }])
+}
+
+fn public_key_validation(public_key: &str) -> serde_json::Value {
+    let mut validation = secret_key_validation();
+    validation["publicKey"] = serde_json::json!({ "value": public_key });
+
+    validation
+}
+
+#[test]
+fn test_no_errors_with_hmac_and_ed25519_gate_contexts() -> Result<()> {
+    for gate_context in [
+        gate_context_with_hmac("bd3862797c3e532f9f07e6672192d46792ee3591a0c7fe279e14d971eb541b37"),
+        ed25519_gate_context(),
+    ] {
+        let result =
+            run_with_gate_context(gate_context, public_key_validation(ED25519_PUBLIC_KEY))?;
+        assert_eq!(result, no_errors());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_no_errors_with_ed25519_gate_context_and_only_a_public_key() -> Result<()> {
+    let mut validation = public_key_validation(ED25519_PUBLIC_KEY);
+    validation["secretKey"] = serde_json::Value::Null;
+
+    let result = run_with_gate_context(ed25519_gate_context(), validation)?;
+    assert_eq!(result, no_errors());
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_ed25519_gate_context_signed_by_another_key() -> Result<()> {
+    let result = run_with_gate_context(
+        ed25519_gate_context(),
+        public_key_validation(OTHER_ED25519_PUBLIC_KEY),
+    )?;
+    assert_eq!(result, gated());
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_ed25519_gate_context_and_no_public_key() -> Result<()> {
+    let result = run_with_gate_context(ed25519_gate_context(), secret_key_validation())?;
+    assert_eq!(result, gated());
+    Ok(())
+}
+
+fn gate_context_with_token_count(token_count: u64) -> serde_json::Value {
+    serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "1e0d2625a0119a1ea8af46dfa755004b80af527f62eac9988d682c5eeebe5ad7",
+        "v": 2,
+        "nonce": "6f1c2a9b0e7d4c35a8b1f0e2d3c4b5a6",
+        "tokenCount": token_count
+    }])
+}
+
+#[test]
+fn test_no_errors_with_token_count_in_gate_context() -> Result<()> {
+    let result = run_with_gate_context(gate_context_with_token_count(3), secret_key_validation())?;
+    assert_eq!(result, no_errors());
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_tampered_token_count_in_gate_context() -> Result<()> {
+    let result = run_with_gate_context(gate_context_with_token_count(10), secret_key_validation())?;
+    assert_eq!(result, gated());
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_unsorted_revocation_list() -> Result<()> {
+    let gate_context = serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "934781cb8d1179b29945c46f2a2d7795acc1211b20a9677cbc5ecc8f08bdb36e",
+        "v": 2,
+        "nonce": "6f1c2a9b0e7d4c35a8b1f0e2d3c4b5a6"
+    }]);
+    let mut validation = secret_key_validation();
+    validation["revokedNonces"] = serde_json::json!({ "value": "[\"ff00\", \"0a1b\"]" });
+
+    let result = run_with_gate_context(gate_context, validation)?;
+    assert_eq!(result, gated());
+    Ok(())
+}
+
+fn aggregate_gate_context(id: Option<&str>, ids: &[&str], hmac: &str) -> serde_json::Value {
+    serde_json::json!([{
+        "id": id,
+        "ids": ids,
+        "hmac": hmac,
+        "v": 2
+    }])
+}
+
+#[test]
+fn test_no_errors_with_aggregate_gate_context() -> Result<()> {
+    let gate_context = aggregate_gate_context(
+        None,
+        &[
+            "gid://shopify/GateConfiguration/2",
+            "gid://shopify/GateConfiguration/1",
+        ],
+        "a587e6e207c419e6dfca8968e57d93c607f49f7f7d20443ed641708dfdf0e60f",
+    );
+
+    let result = run_with_gate_context(gate_context, secret_key_validation())?;
+    assert_eq!(result, no_errors());
+    Ok(())
+}
+
+#[test]
+fn test_no_errors_with_ed25519_aggregate_gate_context() -> Result<()> {
+    let gate_context = serde_json::json!([{
+        "ids": ["gid://shopify/GateConfiguration/1"],
+        "ed25519": "18642cf9db166fe34c4ca280c78b20159bf186cfb17aa02cc5ee80a68df80f9383c5c78aef831236cc89d9030781f392086c9a8ac0cb3f6c410144788128df06",
+        "v": 2
+    }]);
+
+    let result = run_with_gate_context(gate_context, public_key_validation(ED25519_PUBLIC_KEY))?;
+    assert_eq!(result, no_errors());
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_aggregate_gate_context_for_other_gates() -> Result<()> {
+    let gate_context = aggregate_gate_context(
+        None,
+        &["gid://shopify/GateConfiguration/2"],
+        "4bec06e7bad2fec3ebbe1e78fe88e4e46bd71fbab85ad995d702171a746d1fd5",
+    );
+
+    let result = run_with_gate_context(gate_context, secret_key_validation())?;
+    assert_eq!(result, gated());
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_aggregate_gate_context_that_also_has_an_id() -> Result<()> {
+    let gate_context = aggregate_gate_context(
+        Some("gid://shopify/GateConfiguration/1"),
+        &["gid://shopify/GateConfiguration/1"],
+        "4bec06e7bad2fec3ebbe1e78fe88e4e46bd71fbab85ad995d702171a746d1fd5",
+    );
+
+    let result = run_with_gate_context(gate_context, secret_key_validation())?;
+    assert_eq!(result, gated());
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_aggregate_gate_context_and_derived_gate_keys() -> Result<()> {
+    let gate_context = aggregate_gate_context(
+        None,
+        &["gid://shopify/GateConfiguration/1"],
+        "4bec06e7bad2fec3ebbe1e78fe88e4e46bd71fbab85ad995d702171a746d1fd5",
+    );
+    let mut validation = secret_key_validation();
+    validation["secretKey"] = serde_json::json!({
+        "value": "{\"keys\": [{\"secret\": \"secret-key\", \"derivation\": \"hkdf\"}]}"
+    });
+
+    let result = run_with_gate_context(gate_context, validation)?;
+    assert_eq!(result, gated());
+    Ok(())
+}
+
+// Wallet signatures are only verified by the discount function
+#[test]
+fn test_errors_with_personal_sign_gate_context() -> Result<()> {
+    let gate_context = serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "personalSign": format!("{}1b", "ab".repeat(64)),
+        "v": 2,
+        "address": "0x71c7656ec7ab88b098defb751b7401b5f6d8976f"
+    }]);
+
+    let result = run_with_gate_context(gate_context, secret_key_validation())?;
+    assert_eq!(result, gated());
+    Ok(())
+}
+
+fn compact_gate_context(items: serde_json::Value) -> String {
+    let items: Vec<GateContextItem> = serde_json::from_value(items).unwrap();
+
+    compact::encode(&items)
+}
+
+fn gate_context_with_nonce(hmac: &str) -> serde_json::Value {
+    serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": hmac,
+        "v": 2,
+        "nonce": "6f1c2a9b0e7d4c35a8b1f0e2d3c4b5a6"
+    }])
+}
+
+#[test]
+fn test_no_errors_with_compact_gate_context() -> Result<()> {
+    let gate_context = compact_gate_context(gate_context_with_nonce(
+        "934781cb8d1179b29945c46f2a2d7795acc1211b20a9677cbc5ecc8f08bdb36e",
+    ));
+
+    let result = run_with_gate_context(gate_context.into(), secret_key_validation())?;
+    assert_eq!(result, no_errors());
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_tampered_compact_gate_context() -> Result<()> {
+    let gate_context = compact_gate_context(gate_context_with_nonce(
+        "a34781cb8d1179b29945c46f2a2d7795acc1211b20a9677cbc5ecc8f08bdb36e",
+    ));
+
+    let result = run_with_gate_context(gate_context.into(), secret_key_validation())?;
+    assert_eq!(result, gated());
+    Ok(())
+}
+
+#[test]
+fn test_errors_with_revoked_compact_gate_context() -> Result<()> {
+    let gate_context = compact_gate_context(gate_context_with_nonce(
+        "934781cb8d1179b29945c46f2a2d7795acc1211b20a9677cbc5ecc8f08bdb36e",
+    ));
+    let mut validation = secret_key_validation();
+    validation["revokedNonces"] =
+        serde_json::json!({ "value": "[\"6f1c2a9b0e7d4c35a8b1f0e2d3c4b5a6\"]" });
+
+    let result = run_with_gate_context(gate_context.into(), validation)?;
+    assert_eq!(result, gated());
+    Ok(())
+}
@@ -16,15 +16,19 @@ if (host == "YOUR_NGROK_URL") {
   `);
 }
 
+// Path of the app proxy in front of the app's /public routes. Shopify signs the
+// logged in customer into requests through it, which the app binds gate contexts to.
+export const appProxyPath = "/apps/tokengating";
+
 const gateContextClient =
   getGateContextClient({
     backingStore: "ajaxApi",
     shopifyGateContextGenerator: async (data) => {
       try {
         const existing = await gateContextClient.read();
-        return mergeGateContext(existing, data);
+        return gateContextEnvelope(mergeGateContext(gateContextItems(existing), data));
       } catch(e) {
-        return data;
+        return gateContextEnvelope(data);
       }
 
       // merges existing gate context entries
@@ -39,13 +43,23 @@ const gateContextClient =
     },
   });
 
+// Carts may still hold a bare array of items written before the envelope
+function gateContextItems(gateContext) {
+  return Array.isArray(gateContext) ? gateContext : gateContext?.items || [];
+}
+
+// Must match the versions accepted by `parse_gate_context` in the functions
+function gateContextEnvelope(items) {
+  return { v: 2, items };
+}
+
 export const useEvaluateGate = () => {
   const gate = getGate();
   const [gateEvaluation, setGateEvaluation] = useState();
   const productId = getProductId();
   const evaluateGate = useCallback(
     async ({ address, message, signature }) => {
-      const response = await fetch(`${host}/public/gateEvaluation`, {
+      const response = await fetch(`${gateEvaluationBaseUrl()}/gateEvaluation`, {
         method: "POST",
         headers: {
           "Content-Type": "application/json",
@@ -97,3 +111,11 @@ function getShopDomain() {
 function getProductId() {
   return document.getElementById("tokengating-example-app").dataset.product_id;
 }
+
+// Logged in buyers go through the app proxy, so that the app learns who they are
+// from Shopify rather than from the request
+function gateEvaluationBaseUrl() {
+  const { customer_id } = document.getElementById("tokengating-example-app").dataset;
+
+  return customer_id ? appProxyPath : `${host}/public`;
+}
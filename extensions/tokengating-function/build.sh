@@ -0,0 +1,15 @@
+#!/bin/sh
+# Builds the function module and shrinks it with wasm-opt, without which it does not
+# fit in the 256 kB limit on function modules, see "Module size" in the README.
+# Arguments are passed on to cargo, e.g. `sh build.sh --features trace`.
+set -e
+
+cargo build --target=wasm32-wasip1 --release "$@"
+# rustc emits these features, wasm-opt only accepts them when they are enabled
+wasm-opt -Oz \
+  --enable-bulk-memory \
+  --enable-sign-ext \
+  --enable-mutable-globals \
+  --enable-nontrapping-float-to-int \
+  target/wasm32-wasip1/release/tokengating-function.wasm \
+  -o target/wasm32-wasip1/release/tokengating-function.wasm
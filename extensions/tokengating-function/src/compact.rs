@@ -0,0 +1,200 @@
+// Compact encoding of the gate context, for buyers that unlock too many gates for
+// the JSON items to fit in a cart attribute. The value is `c1.` followed by the
+// unpadded base64url encoding of the items, each laid out as:
+//
+// - the numeric ID of the gate configuration, as an unsigned LEB128 varint
+// - a flags byte, see the `FLAG_` constants
+// - the 20 byte wallet address, when flagged
+// - `issuedAt` and `expiresAt` as unsigned LEB128 varints, when flagged
+// - the 16 byte nonce, signed as 32 lowercase hex digits, when flagged
+// - the first 16 bytes of the HMAC of the item's version 2 message
+//
+// Compact items carry no `kid` and are verified with the active keys.
+//
+// The checkout validation example includes this file as its own `compact` module,
+// so it may only use the `GateError`, `BuyerBinding` and `GateContextItem` that
+// both crates define.
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+
+use crate::error::GateError;
+use crate::{BuyerBinding, GateContextItem};
+
+pub const PREFIX: &str = "c1.";
+pub const TRUNCATED_HMAC_LENGTH: usize = 16;
+
+const GATE_CONFIGURATION_ID_PREFIX: &str = "gid://shopify/GateConfiguration/";
+
+const FLAG_ADDRESS: u8 = 0x01;
+const FLAG_VALIDITY_PERIOD: u8 = 0x02;
+const FLAG_CUSTOMER_BINDING: u8 = 0x04;
+const FLAG_EMAIL_BINDING: u8 = 0x08;
+const FLAG_NONCE: u8 = 0x10;
+
+const NONCE_LENGTH: usize = 16;
+
+pub fn decode(value: &str) -> Result<Vec<GateContextItem>, GateError> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(value)
+        .map_err(|error| GateError::MalformedContext(format!("invalid base64url: {}", error)))?;
+
+    let mut remaining = bytes.as_slice();
+    let mut items = vec![];
+    while !remaining.is_empty() {
+        items.push(decode_item(&mut remaining)?);
+    }
+
+    Ok(items)
+}
+
+fn decode_item(bytes: &mut &[u8]) -> Result<GateContextItem, GateError> {
+    let malformed = |reason: &str| GateError::MalformedContext(reason.to_string());
+    let truncated = || malformed("truncated compact item");
+
+    let id = read_varint(bytes).ok_or_else(truncated)?;
+    let flags = take(bytes, 1).ok_or_else(truncated)?[0];
+    if flags
+        & !(FLAG_ADDRESS
+            | FLAG_VALIDITY_PERIOD
+            | FLAG_CUSTOMER_BINDING
+            | FLAG_EMAIL_BINDING
+            | FLAG_NONCE)
+        != 0
+    {
+        return Err(GateError::MalformedContext(format!(
+            "unknown compact item flags {:#04x}",
+            flags
+        )));
+    }
+
+    let address = if flags & FLAG_ADDRESS != 0 {
+        let address = take(bytes, 20).ok_or_else(truncated)?;
+        Some(format!("0x{}", hex::encode(address)))
+    } else {
+        None
+    };
+    let (issued_at, expires_at) = if flags & FLAG_VALIDITY_PERIOD != 0 {
+        let issued_at = read_varint(bytes).ok_or_else(truncated)?;
+        let expires_at = read_varint(bytes).ok_or_else(truncated)?;
+        match (i64::try_from(issued_at), i64::try_from(expires_at)) {
+            (Ok(issued_at), Ok(expires_at)) => (Some(issued_at), Some(expires_at)),
+            _ => return Err(malformed("compact item validity period out of range")),
+        }
+    } else {
+        (None, None)
+    };
+    let binding = match (
+        flags & FLAG_CUSTOMER_BINDING != 0,
+        flags & FLAG_EMAIL_BINDING != 0,
+    ) {
+        (false, false) => None,
+        (true, false) => Some(BuyerBinding::Customer),
+        (false, true) => Some(BuyerBinding::Email),
+        (true, true) => return Err(malformed("compact item bound to two buyer identities")),
+    };
+    let nonce = if flags & FLAG_NONCE != 0 {
+        Some(hex::encode(
+            take(bytes, NONCE_LENGTH).ok_or_else(truncated)?,
+        ))
+    } else {
+        None
+    };
+    let hmac = take(bytes, TRUNCATED_HMAC_LENGTH).ok_or_else(truncated)?;
+
+    Ok(GateContextItem {
+        id: Some(format!("{}{}", GATE_CONFIGURATION_ID_PREFIX, id)),
+        hmac: Some(hex::encode(hmac)),
+        hmac_truncated: true,
+        v: Some(2),
+        binding,
+        address,
+        issued_at,
+        expires_at,
+        nonce,
+        ..GateContextItem::default()
+    })
+}
+
+fn take<'a>(bytes: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+    if bytes.len() < length {
+        return None;
+    }
+
+    let (taken, remaining) = bytes.split_at(length);
+    *bytes = remaining;
+    Some(taken)
+}
+
+// Varints that overflow a u64 are treated like running out of bytes
+fn read_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = take(bytes, 1)?[0];
+        if shift == 63 && byte > 1 {
+            return None;
+        }
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+// Encodes items signed with a version 2 message and a full HMAC, for the tests
+// and the size benchmarks
+#[cfg(test)]
+pub fn encode(items: &[GateContextItem]) -> String {
+    let mut bytes = vec![];
+    for item in items {
+        let id: u64 = item
+            .id
+            .as_ref()
+            .and_then(|id| id.strip_prefix(GATE_CONFIGURATION_ID_PREFIX))
+            .and_then(|id| id.parse().ok())
+            .unwrap();
+        write_varint(&mut bytes, id);
+
+        let mut flags = 0;
+        if item.address.is_some() {
+            flags |= FLAG_ADDRESS;
+        }
+        if item.issued_at.is_some() {
+            flags |= FLAG_VALIDITY_PERIOD;
+        }
+        match item.binding {
+            Some(BuyerBinding::Customer) => flags |= FLAG_CUSTOMER_BINDING,
+            Some(BuyerBinding::Email) => flags |= FLAG_EMAIL_BINDING,
+            _ => {}
+        }
+        if item.nonce.is_some() {
+            flags |= FLAG_NONCE;
+        }
+        bytes.push(flags);
+
+        if let Some(address) = &item.address {
+            bytes.extend(hex::decode(address.trim_start_matches("0x")).unwrap());
+        }
+        if let (Some(issued_at), Some(expires_at)) = (item.issued_at, item.expires_at) {
+            write_varint(&mut bytes, issued_at as u64);
+            write_varint(&mut bytes, expires_at as u64);
+        }
+        if let Some(nonce) = &item.nonce {
+            bytes.extend(hex::decode(nonce).unwrap());
+        }
+        let hmac = hex::decode(item.hmac.as_ref().unwrap()).unwrap();
+        bytes.extend(&hmac[..TRUNCATED_HMAC_LENGTH]);
+    }
+
+    format!("{}{}", PREFIX, URL_SAFE_NO_PAD.encode(bytes))
+}
+
+#[cfg(test)]
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
@@ -0,0 +1,48 @@
+use std::fmt;
+
+// Everything that can be wrong with the input of the function. Parsers return
+// these, and the function decides for each whether it fails closed, only skips the
+// gate concerned or goes on without the value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GateError {
+    // The gate context attribute, or one of its items, does not parse
+    MalformedContext(String),
+    // The gate context envelope names a version this function does not know
+    UnknownVersion(String),
+    // A hex encoded field, like a signature or a key, does not decode
+    BadHex(&'static str),
+    MissingMetafield(&'static str),
+    MalformedMetafield(&'static str, String),
+    // The reaction of a gate configuration cannot be turned into a discount
+    InvalidReaction(String),
+    // The holder has fewer tokens than the lowest tier of the reaction asks for
+    NoTier(u64),
+    // The unit price of the line is already at or below the target price of the
+    // reaction, so there is nothing to take off
+    AtTargetPrice(String),
+}
+
+impl fmt::Display for GateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GateError::MalformedContext(reason) => write!(f, "malformed gate context: {}", reason),
+            GateError::UnknownVersion(version) => {
+                write!(f, "unknown gate context version {}", version)
+            }
+            GateError::BadHex(field) => write!(f, "malformed hex in {}", field),
+            GateError::MissingMetafield(key) => write!(f, "missing {} metafield", key),
+            GateError::MalformedMetafield(key, reason) => {
+                write!(f, "malformed {} metafield: {}", key, reason)
+            }
+            GateError::InvalidReaction(reason) => write!(f, "invalid reaction: {}", reason),
+            GateError::NoTier(token_count) => write!(f, "no tier for {} tokens", token_count),
+            GateError::AtTargetPrice(unit_price) => {
+                write!(
+                    f,
+                    "unit price {} is already at the target price",
+                    unit_price
+                )
+            }
+        }
+    }
+}
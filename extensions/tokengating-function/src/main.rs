@@ -1,71 +1,490 @@
-use std::str::FromStr;
-
 use shopify_function::prelude::*;
 use shopify_function::Result;
 
-use hex;
+#[cfg(feature = "ed25519")]
+use ed25519_compact::{PublicKey, Signature};
+use hkdf::Hkdf;
 use hmac::NewMac;
 use hmac::{Hmac, Mac};
-use serde::{Deserialize, Serialize};
+#[cfg(feature = "personal-sign")]
+use k256::ecdsa::{RecoveryId, VerifyingKey};
+use serde::de::{self, IgnoredAny};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::value::RawValue;
 use sha2::Sha256;
+#[cfg(feature = "personal-sign")]
+use sha3::{Digest, Keccak256};
+#[cfg(feature = "compact")]
+use subtle::ConstantTimeEq;
+
+#[cfg(feature = "compact")]
+mod compact;
+mod error;
+mod merkle;
+mod money;
+mod trace;
+
+use error::GateError;
+use trace::{DecisionTrace, GateTrace, LineTrace};
 
 generate_types!(
     query_path = "./input.graphql",
     schema_path = "./schema.graphql"
 );
 
-const SECRET_KEY: &str = "secret-key"; // This should be a secret key that is shared between the app and the function
-
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
 pub struct GateContextItem {
     pub id: Option<ID>,
+    // Gate configurations unlocked together by a single signature, instead of `id`
+    pub ids: Option<Vec<ID>>,
     pub hmac: Option<String>,
+    // Set for items decoded from the compact encoding, see `compact`
+    #[serde(skip)]
+    pub hmac_truncated: bool,
+    // Hex encoded Ed25519 signature, an alternative to `hmac`
+    pub ed25519: Option<String>,
+    // Hex encoded EIP-191 signature made by the wallet at `address` itself, an
+    // alternative to `hmac` for gates with an allowlist
+    pub personal_sign: Option<String>,
+    pub kid: Option<String>,
+    // Version of the signed message, see `signed_message`
+    pub v: Option<u8>,
+    pub binding: Option<BuyerBinding>,
+    // Wallet that proved ownership of the unlocking tokens
+    pub address: Option<String>,
+    // Allowlisted wallet address or customer ID and its hex encoded Merkle proof,
+    // for gates with a Merkle root in their requirements
+    pub leaf: Option<String>,
+    pub proof: Option<Vec<String>>,
+    // Unix timestamps in seconds
+    pub issued_at: Option<i64>,
+    pub expires_at: Option<i64>,
+    // Identifies the item in the revocation list, see `RevocationList`
+    pub nonce: Option<String>,
+    // How many qualifying tokens the wallet holds, for reactions with tiers
+    pub token_count: Option<u64>,
+}
+
+// Enums without data in the metafields are parsed as `variant_identifier`s, from a
+// plain string, which takes less code than parsing them as enums
+#[derive(Clone, Copy, Debug, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase", variant_identifier)]
+pub enum BuyerBinding {
+    #[default]
+    None,
+    Customer,
+    Email,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FunctionConfiguration {
+    // Gate context items must be bound to this buyer identity to unlock a gate
+    pub buyer_binding: BuyerBinding,
+    // Compatibility switch for gate contexts issued before they were bound to a wallet
+    pub allow_contexts_without_address: bool,
+    // Tolerance applied to `issuedAt` and `expiresAt`. It should cover how often the
+    // app refreshes the reference time metafield, as well as clock differences.
+    pub clock_skew_seconds: i64,
+    // Writes the decision trace for each cart to the function logs
+    pub trace: bool,
+    pub discount_application_strategy: DiscountApplicationStrategy,
+    // Which of the gates that unlocked the same line gives it a discount
+    pub gate_precedence: GatePrecedence,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase", variant_identifier)]
+pub enum DiscountApplicationStrategy {
+    First,
+    #[default]
+    Maximum,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase", variant_identifier)]
+pub enum GatePrecedence {
+    // The gate whose reaction takes the most off the line
+    #[default]
+    BestForCustomer,
+    // The gate that comes first in the discount node's `gate_configuration_id`
+    Priority,
+    // The gate that comes first on the product
+    FirstMatch,
+}
+
+impl Default for FunctionConfiguration {
+    fn default() -> Self {
+        FunctionConfiguration {
+            buyer_binding: BuyerBinding::None,
+            allow_contexts_without_address: false,
+            clock_skew_seconds: 300,
+            trace: false,
+            discount_application_strategy: DiscountApplicationStrategy::Maximum,
+            gate_precedence: GatePrecedence::BestForCustomer,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Keyring {
+    pub keys: Vec<SigningKey>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SigningKey {
+    pub kid: Option<String>,
+    pub secret: String,
+    #[serde(default)]
+    pub status: KeyStatus,
+    #[serde(default)]
+    pub derivation: KeyDerivation,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", variant_identifier)]
+pub enum KeyDerivation {
+    // The secret itself verifies the contexts of every gate
+    #[default]
+    Direct,
+    // Each gate has its own key derived from the secret, see `gate_key`
+    Hkdf,
+}
+
+#[derive(Clone, Debug, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase", variant_identifier)]
+pub enum KeyStatus {
+    // Signs new gate contexts and verifies contexts with or without a `kid`
+    #[default]
+    Active,
+    // Only verifies contexts that name it by `kid`, until they expire from carts
+    Retiring,
+    Revoked,
+}
+
+impl Keyring {
+    fn verification_keys(&self, kid: Option<&str>) -> Vec<&SigningKey> {
+        self.keys
+            .iter()
+            .filter(|key| match kid {
+                Some(kid) => key.kid.as_deref() == Some(kid) && key.status != KeyStatus::Revoked,
+                None => key.status == KeyStatus::Active,
+            })
+            .collect()
+    }
+}
+
+// Built without the `ed25519` feature, no public key can be parsed and no item
+// signed with Ed25519 can be verified
+#[cfg(not(feature = "ed25519"))]
+pub enum PublicKey {}
+
+pub struct VerificationContext<'a> {
+    pub keyring: Option<&'a Keyring>,
+    pub public_key: Option<&'a PublicKey>,
+    pub configuration: &'a FunctionConfiguration,
+    pub buyer_identity: Option<&'a input::InputCartBuyerIdentity>,
+    // Unix timestamp in seconds that the app refreshes regularly, functions have no clock
+    pub reference_time: Option<i64>,
+    pub revocation_list: &'a RevocationList,
+}
+
+// Nonces of leaked gate contexts, which unlock no gate even though their signature
+// is valid. The app stores them sorted, so that each lookup is a binary search
+// without sorting the list first.
+#[derive(Clone, Debug, Default)]
+pub struct RevocationList {
+    nonces: Vec<String>,
+}
+
+impl RevocationList {
+    // The binary search could miss a revoked nonce in a list that is not sorted
+    fn new(nonces: Vec<String>) -> std::result::Result<RevocationList, GateError> {
+        if nonces.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(GateError::MalformedMetafield(
+                "revoked_nonces",
+                "nonces are not sorted".to_string(),
+            ));
+        }
+
+        Ok(RevocationList { nonces })
+    }
+
+    fn is_revoked(&self, nonce: &str) -> bool {
+        self.nonces
+            .binary_search_by(|revoked| revoked.as_str().cmp(nonce))
+            .is_ok()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct GateRequirements {
+    // Wallets that unlock the gate by signing the gate context themselves
+    pub allowlist: Vec<String>,
+    // Hex encoded root of a Merkle tree over `merkle_leaves`, see `merkle`
+    pub merkle_root: Option<String>,
+    pub merkle_leaves: MerkleLeaves,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", variant_identifier)]
+pub enum MerkleLeaves {
+    // Leaves are wallets, which also have to sign the gate context
+    #[default]
+    Address,
+    // Leaves are customers, which have to be the buyer of the cart
+    Customer,
 }
 
 #[derive(Clone, Debug, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
 pub struct GateReaction {
     pub name: String,
     pub discount: Discount,
+    // Discounts that replace `discount` on lines with at least `minQuantity` units
+    #[serde(default)]
+    pub quantity_tiers: Vec<QuantityTier>,
+    // Only this many units of each line are discounted, e.g. one per holder
+    pub max_discounted_units: Option<i64>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(rename_all = "camelCase", tag = "type")]
+#[serde(rename_all = "camelCase")]
+pub struct QuantityTier {
+    pub min_quantity: i64,
+    pub discount: Discount,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "DiscountFields")]
 pub enum Discount {
-    Percentage { value: StringNumberOrNumber },
-    Amount { value: StringNumberOrNumber },
+    Percentage {
+        value: StringNumberOrNumber,
+    },
+    Amount {
+        value: StringNumberOrNumber,
+        // Takes the amount off each discounted unit, rather than spreading it
+        // across all of them
+        applies_to_each_item: Option<bool>,
+    },
+    // Deeper discounts for holders of more tokens
+    Tiers {
+        tiers: Vec<DiscountTier>,
+    },
+    // The price holders pay for each unit, in the currency of the shop
+    TargetPrice {
+        value: StringNumberOrNumber,
+    },
+}
+
+// A discount as it is written in the metafield, with the fields of every type. An
+// internally tagged enum would buffer each discount before parsing it, which takes
+// far more code, as discounts nest in tiers.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DiscountFields {
+    #[serde(rename = "type")]
+    discount_type: DiscountType,
+    value: Option<StringNumberOrNumber>,
+    applies_to_each_item: Option<bool>,
+    tiers: Option<Vec<DiscountTier>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", variant_identifier)]
+enum DiscountType {
+    Percentage,
+    Amount,
+    Tiers,
+    TargetPrice,
+}
+
+impl TryFrom<DiscountFields> for Discount {
+    type Error = &'static str;
+
+    fn try_from(fields: DiscountFields) -> std::result::Result<Self, Self::Error> {
+        match (fields.discount_type, fields.value, fields.tiers) {
+            (DiscountType::Tiers, _, Some(tiers)) => Ok(Discount::Tiers { tiers }),
+            (DiscountType::Tiers, _, None) => Err("missing field `tiers`"),
+            (_, None, _) => Err("missing field `value`"),
+            (DiscountType::Percentage, Some(value), _) => Ok(Discount::Percentage { value }),
+            (DiscountType::Amount, Some(value), _) => Ok(Discount::Amount {
+                value,
+                applies_to_each_item: fields.applies_to_each_item,
+            }),
+            (DiscountType::TargetPrice, Some(value), _) => Ok(Discount::TargetPrice { value }),
+        }
+    }
+}
+
+// The discount a reaction gives one line, once its tiers and target price have been
+// resolved for that line, see `GateReaction::for_line`
+#[derive(Clone, Debug)]
+pub enum LineDiscount {
+    Percentage {
+        value: StringNumberOrNumber,
+    },
+    Amount {
+        value: StringNumberOrNumber,
+        applies_to_each_item: Option<bool>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct LineReaction {
+    pub name: String,
+    pub discount: LineDiscount,
+    pub max_discounted_units: Option<i64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiscountTier {
+    pub min_tokens: u64,
+    pub discount: Discount,
+}
+
+impl Discount {
+    // The discount of the tier with the most tokens that the holder has, if any
+    fn for_token_count(&self, token_count: u64) -> Option<&Discount> {
+        match self {
+            Discount::Tiers { tiers } => tiers
+                .iter()
+                .filter(|tier| tier.min_tokens <= token_count)
+                .max_by_key(|tier| tier.min_tokens)
+                .map(|tier| &tier.discount),
+            discount => Some(discount),
+        }
+    }
+}
+
+impl GateReaction {
+    // The discount for a line, from the quantity tier with the most units that the
+    // line has and then from the tier with the most tokens that the holder has.
+    // Target prices become the amount that has to come off each unit of the line,
+    // and give no discount on lines that already cost less.
+    fn for_line(
+        self,
+        token_count: u64,
+        line: &input::InputCartLines,
+        presentment_currency_rate: &str,
+    ) -> std::result::Result<LineReaction, GateError> {
+        let discount = self
+            .quantity_tiers
+            .iter()
+            .filter(|tier| tier.min_quantity <= line.quantity)
+            .max_by_key(|tier| tier.min_quantity)
+            .map_or(&self.discount, |tier| &tier.discount)
+            .for_token_count(token_count)
+            .cloned()
+            .ok_or(GateError::NoTier(token_count))?;
+
+        let discount = match discount {
+            Discount::TargetPrice { value } => {
+                let unit_price = &line.cost.amount_per_quantity;
+                let amount = money::amount_off_to_reach(
+                    &unit_price.amount,
+                    &value.to_string(),
+                    presentment_currency_rate,
+                    money::currency_decimals(&unit_price.currency_code),
+                )
+                .ok_or_else(|| {
+                    GateError::InvalidReaction(format!(
+                        "target price {} cannot be reached from {}",
+                        value, unit_price.amount
+                    ))
+                })?;
+                // A discount of nothing would still take precedence over other gates
+                if money::is_zero(&amount) {
+                    return Err(GateError::AtTargetPrice(unit_price.amount.clone()));
+                }
+                LineDiscount::Amount {
+                    value: StringNumberOrNumber::StringNumber(amount),
+                    applies_to_each_item: Some(true),
+                }
+            }
+            Discount::Percentage { value } => LineDiscount::Percentage { value },
+            Discount::Amount {
+                value,
+                applies_to_each_item,
+            } => LineDiscount::Amount {
+                value,
+                applies_to_each_item,
+            },
+            // Tiers within a tier are rejected when the reaction is parsed
+            Discount::Tiers { .. } => {
+                return Err(GateError::InvalidReaction(
+                    "tiers within a tier".to_string(),
+                ))
+            }
+        };
+
+        Ok(LineReaction {
+            name: self.name,
+            discount,
+            max_discounted_units: self.max_discounted_units,
+        })
+    }
 }
 
 impl Default for Discount {
     fn default() -> Self {
         Discount::Percentage {
-            value: StringNumberOrNumber::Number(0.0f64),
+            value: StringNumberOrNumber::Number("0".to_string()),
         }
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(untagged)]
+// Numbers are passed on as they are written in the metafield and compared as exact
+// decimals, see `money`. Parsing and printing floats would take about 30 kB of the
+// module.
+#[derive(Clone, Debug)]
 pub enum StringNumberOrNumber {
     StringNumber(String),
-    Number(f64),
+    Number(String),
 }
 
-impl TryInto<f64> for StringNumberOrNumber {
-    type Error = <f64 as FromStr>::Err;
+impl<'de> Deserialize<'de> for StringNumberOrNumber {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let value = Box::<RawValue>::deserialize(deserializer)?;
+        let value = value.get();
+        if value.starts_with('"') {
+            serde_json::from_str(value)
+                .map(StringNumberOrNumber::StringNumber)
+                .map_err(de::Error::custom)
+        } else if value.starts_with(|c: char| c == '-' || c.is_ascii_digit()) {
+            Ok(StringNumberOrNumber::Number(value.to_string()))
+        } else {
+            Err(de::Error::invalid_type(
+                de::Unexpected::Other(value),
+                &"a string or a number",
+            ))
+        }
+    }
+}
 
-    fn try_into(self) -> std::result::Result<f64, Self::Error> {
+impl StringNumberOrNumber {
+    // The value scaled to an integer, if it is a non-negative decimal that can be
+    // passed on as it is written
+    fn to_decimal(&self) -> Option<i128> {
         match self {
-            StringNumberOrNumber::Number(v) => Ok(v),
-            StringNumberOrNumber::StringNumber(str) => Ok(f64::from_str(&str)?),
+            StringNumberOrNumber::Number(str) | StringNumberOrNumber::StringNumber(str)
+                if str.trim() == str =>
+            {
+                money::parse_decimal(str)
+            }
+            _ => None,
         }
     }
 }
 
-impl ToString for StringNumberOrNumber {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for StringNumberOrNumber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            StringNumberOrNumber::Number(v) => v.to_string(),
-            StringNumberOrNumber::StringNumber(str) => str.clone(),
+            StringNumberOrNumber::Number(str) => write!(f, "{}", str),
+            StringNumberOrNumber::StringNumber(str) => write!(f, "{}", str),
         }
     }
 }
@@ -77,128 +496,1114 @@ const NO_DISCOUNT: output::FunctionResult = output::FunctionResult {
 
 #[shopify_function]
 fn function(input: input::ResponseData) -> Result<output::FunctionResult> {
+    let (result, trace) = evaluate_gates(input);
+    if let Some(trace) = trace {
+        for record in trace.to_log_records() {
+            eprintln!("{}", record);
+        }
+    }
+
+    Ok(result)
+}
+
+// The decision trace is only recorded when the configuration metafield sets
+// `"trace": true`, or when the function is built with the `trace` feature
+fn evaluate_gates(input: input::ResponseData) -> (output::FunctionResult, Option<DecisionTrace>) {
     let cart_lines = input.cart.lines;
-    let gate_context = parse_gate_context_from_cart_attribute(&input.cart.attribute);
-    let discount_gate_configuration_id = input.discount_node.metafield.unwrap().value;
+    // The configuration comes first, as it says whether to trace the other failures
+    let configuration =
+        match parse_configuration_from_metafield(input.discount_node.configuration.as_ref()) {
+            Ok(configuration) => configuration,
+            Err(error) => {
+                eprintln!("No gate can be unlocked: {}", error);
+                return no_gate_unlocked(
+                    cfg!(feature = "trace").then(DecisionTrace::default),
+                    error,
+                );
+            }
+        };
+    let mut trace = (configuration.trace || cfg!(feature = "trace")).then(DecisionTrace::default);
 
-    if cart_lines.is_empty() || gate_context.is_empty() {
-        return Ok(NO_DISCOUNT);
+    let gate_context = match parse_gate_context_from_cart_attribute(&input.cart.attribute) {
+        Ok(gate_context) => gate_context,
+        Err(error) => {
+            eprintln!("Rejected gate context, no gate can be unlocked: {}", error);
+            return no_gate_unlocked(trace, error);
+        }
+    };
+    // A discount that does not say which gates it covers gives no discount, rather
+    // than failing the checkout
+    let discount_gate_configuration_ids =
+        match parse_gate_configuration_ids_from_metafield(input.discount_node.metafield.as_ref()) {
+            Ok(gate_configuration_ids) => gate_configuration_ids,
+            Err(error) => {
+                eprintln!("No gate can be unlocked: {}", error);
+                return no_gate_unlocked(trace, error);
+            }
+        };
+
+    // Carts without a gate context are only looked at to trace them
+    if cart_lines.is_empty() || (gate_context.is_empty() && trace.is_none()) {
+        return (NO_DISCOUNT, trace);
+    }
+
+    // Gate contexts are signed by the app, either with a secret key shared between
+    // the app and the function or with a private key only known to the app. Without
+    // any key only gates with an allowlist can be unlocked, by the wallets on it.
+    let keyring = optional_metafield(parse_keyring_from_metafield(
+        input.discount_node.secret_key.as_ref(),
+    ));
+    let public_key = optional_metafield(parse_public_key_from_metafield(
+        input.discount_node.public_key.as_ref(),
+    ));
+    if keyring.is_none() && public_key.is_none() {
+        eprintln!("Missing secret_key and public_key metafields on the discount node, only allowlisted wallets can unlock a gate");
     }
 
-    let mut targets: Vec<output::Target> = vec![];
-    let mut gate_reaction: GateReaction = GateReaction::default();
+    // A revocation list that cannot be read could let leaked gate contexts through
+    let revocation_list =
+        match parse_revocation_list_from_metafield(input.discount_node.revoked_nonces.as_ref()) {
+            Ok(revocation_list) => revocation_list,
+            Err(error) => {
+                eprintln!("No gate can be unlocked: {}", error);
+                return no_gate_unlocked(trace, error);
+            }
+        };
+
+    let verification_context = VerificationContext {
+        keyring: keyring.as_ref(),
+        public_key: public_key.as_ref(),
+        configuration: &configuration,
+        buyer_identity: input.cart.buyer_identity.as_ref(),
+        reference_time: optional_metafield(parse_reference_time_from_metafield(
+            input.discount_node.reference_time.as_ref(),
+        )),
+        revocation_list: &revocation_list,
+    };
+
+    let unlocked_by_aggregates =
+        aggregate_unlocked_gate_configuration_ids(&gate_context, &verification_context);
+
+    // One discount per reaction, each with the targets that were unlocked with it
+    let mut discounts: Vec<output::Discount> = vec![];
 
-    let product_variants = cart_lines.iter().flat_map(|line| {
+    for line in &cart_lines {
         // Merchandise is a union type, so we need to match on the variant
-        match &line.merchandise {
-            input::InputCartLinesMerchandise::ProductVariant(variant) => Some(variant),
-            _ => None,
-        }
-    });
+        let product_variant = match &line.merchandise {
+            input::InputCartLinesMerchandise::ProductVariant(variant) => variant,
+            _ => continue,
+        };
+        let mut gate_traces = vec![];
+        let mut unlocked_gates = vec![];
 
-    for product_variant in product_variants {
         for gate_subject in &product_variant.product.gates {
             let gate_configuration = &gate_subject.configuration;
             let gate_context_item = gate_context.iter().find(|gate_context_item| {
                 gate_context_item.id == Some(gate_configuration.id.to_string())
             });
-            let gate_unlocked = match &gate_context_item {
-                Some(ctx) => {
-                    is_signature_valid(ctx, &gate_configuration)
-                        && gate_configuration.id == discount_gate_configuration_id
-                }
-                _ => false,
+            let unlocked_by_aggregate = unlocked_by_aggregates.contains(&gate_configuration.id);
+            let priority = discount_gate_configuration_ids
+                .iter()
+                .position(|id| id == &gate_configuration.id);
+            // The item for the gate is verified even when an aggregate item unlocked
+            // it, as only that item can vouch for a token count
+            let verified_item = priority.and(gate_context_item).filter(|ctx| {
+                is_signature_valid(
+                    ctx,
+                    &gate_configuration.id,
+                    gate_configuration.requirements.as_ref(),
+                    &verification_context,
+                )
+            });
+            let gate_unlocked =
+                priority.is_some() && (unlocked_by_aggregate || verified_item.is_some());
+            let mut gate_trace = GateTrace {
+                gate_subject_id: gate_subject.id.to_string(),
+                gate_configuration_id: gate_configuration.id.to_string(),
+                covered_by_discount: priority.is_some(),
+                context_item_found: gate_context_item.is_some() || unlocked_by_aggregate,
+                signature_verified: gate_unlocked,
+                ..GateTrace::default()
             };
 
             if gate_unlocked {
-                gate_reaction =
-                    parse_gate_reaction_from_metafield(gate_configuration.metafield.as_ref());
-
-                targets.push(output::Target::ProductVariant(
-                    output::ProductVariantTarget {
-                        id: product_variant.id.to_string(),
-                        quantity: None,
-                    },
+                // Only a verified item for the gate can vouch for a token count.
+                // Aggregate items and items without one stand for a single token.
+                let token_count = verified_item.and_then(|ctx| ctx.token_count).unwrap_or(1);
+
+                // A gate without a usable reaction has no discount to give
+                match parse_gate_reaction_from_metafield(gate_configuration.metafield.as_ref())
+                    .and_then(|gate_reaction| {
+                        gate_reaction.for_line(token_count, line, &input.presentment_currency_rate)
+                    }) {
+                    Ok(gate_reaction) => {
+                        if let Some(address) = verified_item.and_then(|ctx| ctx.address.as_ref()) {
+                            eprintln!("{} unlocked by wallet {}", gate_configuration.id, address);
+                        }
+
+                        unlocked_gates.push(UnlockedGate {
+                            trace_index: gate_traces.len(),
+                            gate_configuration_id: &gate_configuration.id,
+                            priority: priority.unwrap_or_default(),
+                            gate_reaction,
+                        });
+                    }
+                    Err(error) => {
+                        eprintln!("Skipping {}: {}", gate_configuration.id, error);
+                        gate_trace.error = Some(error.to_string());
+                    }
+                }
+            }
+
+            gate_traces.push(gate_trace);
+        }
+
+        if let Some(unlocked_gate) =
+            take_precedence(&unlocked_gates, configuration.gate_precedence, line)
+        {
+            for other_gate in &unlocked_gates {
+                gate_traces[other_gate.trace_index].error = Some(format!(
+                    "{} took precedence",
+                    unlocked_gate.gate_configuration_id
                 ));
             }
+            let gate_trace = &mut gate_traces[unlocked_gate.trace_index];
+            gate_trace.error = None;
+            gate_trace.reaction = Some(unlocked_gate.gate_reaction.name.clone());
+
+            add_target(
+                &mut discounts,
+                unlocked_gate.gate_reaction.clone(),
+                output::Target::ProductVariant(output::ProductVariantTarget {
+                    id: product_variant.id.to_string(),
+                    quantity: discounted_units(&unlocked_gate.gate_reaction, line),
+                }),
+            );
+        }
+
+        if let Some(trace) = trace.as_mut() {
+            trace.lines.push(LineTrace {
+                variant_id: product_variant.id.to_string(),
+                gates: gate_traces,
+            });
         }
     }
 
-    if targets.is_empty() {
-        return Ok(NO_DISCOUNT);
+    if discounts.is_empty() {
+        return (NO_DISCOUNT, trace);
     }
 
-    let value = reaction_value(gate_reaction.clone());
-    let message = Some(gate_reaction.name);
+    let result = output::FunctionResult {
+        discounts,
+        discount_application_strategy: match configuration.discount_application_strategy {
+            DiscountApplicationStrategy::First => output::DiscountApplicationStrategy::FIRST,
+            DiscountApplicationStrategy::Maximum => output::DiscountApplicationStrategy::MAXIMUM,
+        },
+    };
+    (result, trace)
+}
+
+// Failures that keep every gate locked are recorded as the error of the trace
+fn no_gate_unlocked(
+    mut trace: Option<DecisionTrace>,
+    error: GateError,
+) -> (output::FunctionResult, Option<DecisionTrace>) {
+    if let Some(trace) = trace.as_mut() {
+        trace.error = Some(error.to_string());
+    }
 
-    Ok(output::FunctionResult {
-        discounts: vec![output::Discount {
-            message: message,
-            targets: targets,
-            value: value,
-        }],
-        discount_application_strategy: output::DiscountApplicationStrategy::MAXIMUM,
-    })
+    (NO_DISCOUNT, trace)
+}
+
+struct UnlockedGate<'a> {
+    trace_index: usize,
+    gate_configuration_id: &'a str,
+    // The position of the gate configuration in the discount node's metafield
+    priority: usize,
+    gate_reaction: LineReaction,
+}
+
+// Only one of the gates that unlocked a line gives it a discount. Ties go to the
+// gate that comes first on the product.
+fn take_precedence<'a, 'b>(
+    unlocked_gates: &'b [UnlockedGate<'a>],
+    gate_precedence: GatePrecedence,
+    line: &input::InputCartLines,
+) -> Option<&'b UnlockedGate<'a>> {
+    let unlocked_gates = unlocked_gates.iter();
+    match gate_precedence {
+        GatePrecedence::FirstMatch => unlocked_gates.reduce(|first, _| first),
+        GatePrecedence::Priority => unlocked_gates.reduce(|best, unlocked_gate| {
+            if unlocked_gate.priority < best.priority {
+                unlocked_gate
+            } else {
+                best
+            }
+        }),
+        GatePrecedence::BestForCustomer => unlocked_gates.reduce(|best, unlocked_gate| {
+            if reaction_savings(&unlocked_gate.gate_reaction, line)
+                > reaction_savings(&best.gate_reaction, line)
+            {
+                unlocked_gate
+            } else {
+                best
+            }
+        }),
+    }
+}
+
+// All units of the line are discounted, unless the reaction caps them
+fn discounted_units(gate_reaction: &LineReaction, line: &input::InputCartLines) -> Option<i64> {
+    gate_reaction
+        .max_discounted_units
+        .map(|max_discounted_units| max_discounted_units.min(line.quantity))
+}
+
+// What a reaction takes off the line, to compare reactions of different types.
+// Amounts are taken off the line once, or once per unit when they apply to each
+// item, and never beyond its cost.
+fn reaction_savings(gate_reaction: &LineReaction, line: &input::InputCartLines) -> i128 {
+    let units = i128::from(discounted_units(gate_reaction, line).unwrap_or(line.quantity));
+    let line_cost = money::parse_decimal(&line.cost.amount_per_quantity.amount)
+        .unwrap_or_default()
+        .saturating_mul(units);
+
+    match &gate_reaction.discount {
+        LineDiscount::Percentage { value } => {
+            money::percentage_of(line_cost, value.to_decimal().unwrap_or_default())
+        }
+        LineDiscount::Amount {
+            value,
+            applies_to_each_item,
+        } => {
+            let amount = value.to_decimal().unwrap_or_default();
+            if *applies_to_each_item == Some(true) {
+                amount.saturating_mul(units).min(line_cost)
+            } else {
+                amount.min(line_cost)
+            }
+        }
+    }
 }
 
+// Reactions that give the same discount under the same name share an
+// `output::Discount`, in the order they were first unlocked in
+fn add_target(
+    discounts: &mut Vec<output::Discount>,
+    gate_reaction: LineReaction,
+    target: output::Target,
+) {
+    let message = Some(gate_reaction.name.clone());
+    let value = reaction_value(gate_reaction);
+
+    match discounts
+        .iter_mut()
+        .find(|discount| discount.message == message && discount.value == value)
+    {
+        Some(discount) => discount.targets.push(target),
+        None => discounts.push(output::Discount {
+            message,
+            targets: vec![target],
+            value,
+        }),
+    }
+}
+
+// A cart without the attribute has no gate context, rather than a malformed one
 fn parse_gate_context_from_cart_attribute(
     attribute: &Option<input::InputCartAttribute>,
-) -> Vec<GateContextItem> {
-    attribute
-        .as_ref()
-        .and_then(|a| a.value.as_ref())
-        .map(|value| serde_json::from_str(&value).unwrap_or_default())
-        .unwrap_or_else(|| vec![])
+) -> std::result::Result<Vec<GateContextItem>, GateError> {
+    match attribute.as_ref().and_then(|a| a.value.as_ref()) {
+        Some(value) if !value.trim().is_empty() => parse_gate_context(value),
+        _ => Ok(vec![]),
+    }
+}
+
+// Storefronts used to write a bare array of items, which is version 1 of the
+// format. Later versions wrap the items in an envelope that names its version, so
+// the format can change without breaking storefronts that still write an older one.
+fn parse_gate_context(value: &str) -> std::result::Result<Vec<GateContextItem>, GateError> {
+    #[cfg(feature = "compact")]
+    if let Some(compact) = value.strip_prefix(compact::PREFIX) {
+        return compact::decode(compact);
+    }
+
+    match value.trim_start().as_bytes().first() {
+        Some(b'[') => serde_json::from_str(value)
+            .map_err(|error| GateError::MalformedContext(format!("invalid items: {}", error))),
+        Some(b'{') => {
+            let envelope: GateContextEnvelope = serde_json::from_str(value)
+                .map_err(|error| GateError::MalformedContext(format!("invalid JSON: {}", error)))?;
+            match envelope.v.as_deref().map(RawValue::get) {
+                Some("2") => parse_gate_context_v2(envelope.items.as_deref()),
+                Some(v) => Err(GateError::UnknownVersion(v.to_string())),
+                None => Err(GateError::MalformedContext(
+                    "envelope without a version".to_string(),
+                )),
+            }
+        }
+        _ => match serde_json::from_str::<IgnoredAny>(value) {
+            Ok(_) => Err(GateError::MalformedContext(
+                "neither an array of items nor an envelope".to_string(),
+            )),
+            Err(error) => Err(GateError::MalformedContext(format!(
+                "invalid JSON: {}",
+                error
+            ))),
+        },
+    }
+}
+
+// The items are left unparsed until the version of the envelope is known
+#[derive(Deserialize)]
+struct GateContextEnvelope {
+    v: Option<Box<RawValue>>,
+    items: Option<Box<RawValue>>,
+}
+
+fn parse_gate_context_v2(
+    items: Option<&RawValue>,
+) -> std::result::Result<Vec<GateContextItem>, GateError> {
+    let items = items.ok_or_else(|| {
+        GateError::MalformedContext("invalid version 2 envelope: missing items".to_string())
+    })?;
+
+    serde_json::from_str(items.get()).map_err(|error| {
+        GateError::MalformedContext(format!("invalid version 2 envelope: {}", error))
+    })
+}
+
+// One discount can cover several gate configurations. The metafield holds either a
+// single ID or a JSON array of IDs.
+fn parse_gate_configuration_ids_from_metafield(
+    metafield: Option<&input::InputDiscountNodeMetafield>,
+) -> std::result::Result<Vec<String>, GateError> {
+    let value = match metafield.map(|metafield| metafield.value.trim()) {
+        Some(value) if !value.is_empty() => value,
+        _ => return Err(GateError::MissingMetafield("gate_configuration_id")),
+    };
+
+    let gate_configuration_ids: Vec<String> = if value.starts_with('[') {
+        serde_json::from_str(value).map_err(|error| {
+            GateError::MalformedMetafield("gate_configuration_id", error.to_string())
+        })?
+    } else {
+        vec![value.to_string()]
+    };
+
+    match gate_configuration_ids
+        .iter()
+        .find(|id| !id.starts_with("gid://shopify/GateConfiguration/"))
+    {
+        Some(id) => Err(GateError::MalformedMetafield(
+            "gate_configuration_id",
+            format!("{:?} is not a gate configuration ID", id),
+        )),
+        None if gate_configuration_ids.is_empty() => Err(GateError::MalformedMetafield(
+            "gate_configuration_id",
+            "no gate configuration ID".to_string(),
+        )),
+        None => Ok(gate_configuration_ids),
+    }
+}
+
+// Metafields that the function can do without are only logged when malformed
+fn optional_metafield<T>(result: std::result::Result<T, GateError>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(GateError::MissingMetafield(_)) => None,
+        Err(error) => {
+            eprintln!("Ignoring {}", error);
+            None
+        }
+    }
+}
+
+fn parse_keyring_from_metafield(
+    metafield: Option<&input::InputDiscountNodeSecretKey>,
+) -> std::result::Result<Keyring, GateError> {
+    let value = match metafield.map(|metafield| metafield.value.trim()) {
+        Some(value) if !value.is_empty() => value,
+        _ => return Err(GateError::MissingMetafield("secret_key")),
+    };
+
+    // A bare secret is a keyring with a single active key and no `kid`
+    let keyring = if value.starts_with('{') {
+        serde_json::from_str(value)
+            .map_err(|error| GateError::MalformedMetafield("secret_key", error.to_string()))?
+    } else {
+        Keyring {
+            keys: vec![SigningKey {
+                kid: None,
+                secret: value.to_string(),
+                status: KeyStatus::Active,
+                derivation: KeyDerivation::Direct,
+            }],
+        }
+    };
+
+    let has_usable_key = keyring
+        .keys
+        .iter()
+        .any(|key| key.status != KeyStatus::Revoked && !key.secret.is_empty());
+    if has_usable_key {
+        Ok(keyring)
+    } else {
+        Err(GateError::MalformedMetafield(
+            "secret_key",
+            "no usable key".to_string(),
+        ))
+    }
+}
+
+#[cfg(feature = "ed25519")]
+fn parse_public_key_from_metafield(
+    metafield: Option<&input::InputDiscountNodePublicKey>,
+) -> std::result::Result<PublicKey, GateError> {
+    let value = metafield
+        .map(|metafield| metafield.value.trim())
+        .ok_or(GateError::MissingMetafield("public_key"))?;
+    let bytes = decode_hex("public_key", value)?;
+
+    PublicKey::from_slice(&bytes).map_err(|_| {
+        GateError::MalformedMetafield("public_key", "not an Ed25519 public key".to_string())
+    })
+}
+
+#[cfg(not(feature = "ed25519"))]
+fn parse_public_key_from_metafield(
+    metafield: Option<&input::InputDiscountNodePublicKey>,
+) -> std::result::Result<PublicKey, GateError> {
+    match metafield {
+        Some(_) => Err(GateError::MalformedMetafield(
+            "public_key",
+            "the function is built without the ed25519 feature".to_string(),
+        )),
+        None => Err(GateError::MissingMetafield("public_key")),
+    }
+}
+
+// A missing metafield is the default configuration
+fn parse_configuration_from_metafield(
+    metafield: Option<&input::InputDiscountNodeConfiguration>,
+) -> std::result::Result<FunctionConfiguration, GateError> {
+    match metafield {
+        Some(metafield) => serde_json::from_str(metafield.value.as_str())
+            .map_err(|error| GateError::MalformedMetafield("configuration", error.to_string())),
+        None => Ok(FunctionConfiguration::default()),
+    }
+}
+
+fn parse_reference_time_from_metafield(
+    metafield: Option<&input::InputDiscountNodeReferenceTime>,
+) -> std::result::Result<i64, GateError> {
+    let metafield = metafield.ok_or(GateError::MissingMetafield("reference_time"))?;
+    metafield
+        .value
+        .trim()
+        .parse()
+        .map_err(|error: std::num::ParseIntError| {
+            GateError::MalformedMetafield("reference_time", error.to_string())
+        })
+}
+
+// A missing metafield is an empty revocation list
+fn parse_revocation_list_from_metafield(
+    metafield: Option<&input::InputDiscountNodeRevokedNonces>,
+) -> std::result::Result<RevocationList, GateError> {
+    match metafield {
+        Some(metafield) => serde_json::from_str(&metafield.value)
+            .map_err(|error| GateError::MalformedMetafield("revoked_nonces", error.to_string()))
+            .and_then(RevocationList::new),
+        None => Ok(RevocationList::default()),
+    }
 }
 
 fn parse_gate_reaction_from_metafield(
     metafield: Option<
         &input::InputCartLinesMerchandiseOnProductVariantProductGatesConfigurationMetafield,
     >,
-) -> GateReaction {
-    metafield
-        .map(|metafield| serde_json::from_str(metafield.value.as_str()).unwrap())
-        .unwrap_or_default()
+) -> std::result::Result<GateReaction, GateError> {
+    let metafield = metafield.ok_or(GateError::MissingMetafield("reaction"))?;
+    let gate_reaction: GateReaction = serde_json::from_str(metafield.value.as_str())
+        .map_err(|error| GateError::InvalidReaction(error.to_string()))?;
+
+    validate_discount(&gate_reaction.discount)?;
+    for tier in &gate_reaction.quantity_tiers {
+        validate_discount(&tier.discount)?;
+    }
+    match gate_reaction.max_discounted_units {
+        Some(max_discounted_units) if max_discounted_units < 1 => {
+            Err(GateError::InvalidReaction(format!(
+                "maxDiscountedUnits {} is not a positive number of units",
+                max_discounted_units
+            )))
+        }
+        _ => Ok(gate_reaction),
+    }
+}
+
+fn validate_discount(discount: &Discount) -> std::result::Result<(), GateError> {
+    match discount {
+        // The value is passed on as a string, so check here that it is a number
+        Discount::Percentage { value }
+        | Discount::Amount { value, .. }
+        | Discount::TargetPrice { value } => match value.to_decimal() {
+            Some(_) => Ok(()),
+            None => Err(GateError::InvalidReaction(format!(
+                "discount value {} is not a number",
+                value
+            ))),
+        },
+        Discount::Tiers { tiers } if tiers.is_empty() => {
+            Err(GateError::InvalidReaction("no tiers".to_string()))
+        }
+        Discount::Tiers { tiers } => tiers.iter().try_for_each(|tier| match tier.discount {
+            Discount::Tiers { .. } => Err(GateError::InvalidReaction(
+                "tiers within a tier".to_string(),
+            )),
+            _ => validate_discount(&tier.discount),
+        }),
+    }
+}
+
+// A missing metafield means the gate has no requirements
+fn parse_gate_requirements_from_metafield(
+    metafield: Option<
+        &input::InputCartLinesMerchandiseOnProductVariantProductGatesConfigurationRequirements,
+    >,
+) -> std::result::Result<GateRequirements, GateError> {
+    match metafield {
+        Some(metafield) => serde_json::from_str(&metafield.value)
+            .map_err(|error| GateError::MalformedMetafield("requirements", error.to_string())),
+        None => Ok(GateRequirements::default()),
+    }
+}
+
+fn decode_hex(field: &'static str, value: &str) -> std::result::Result<Vec<u8>, GateError> {
+    hex::decode(value).map_err(|_| GateError::BadHex(field))
+}
+
+// Aggregate items sign many gate configurations at once, so they are verified once
+// per execution rather than once per gated line
+fn aggregate_unlocked_gate_configuration_ids(
+    gate_context: &[GateContextItem],
+    verification_context: &VerificationContext,
+) -> Vec<ID> {
+    let mut unlocked = Vec::new();
+    for gate_context_item in gate_context {
+        let ids = match &gate_context_item.ids {
+            Some(ids) => ids,
+            None => continue,
+        };
+
+        // Wallet signatures and Merkle proofs are checked against the requirements of
+        // a single gate, so aggregate items are only verified with the app's keys
+        if is_signature_valid(gate_context_item, "", None, verification_context) {
+            if let Some(address) = &gate_context_item.address {
+                eprintln!("{} unlocked by wallet {}", ids.join(", "), address);
+            }
+            unlocked.extend(ids.iter().cloned());
+        }
+    }
+
+    unlocked
 }
 
 fn is_signature_valid(
     gate_context_item: &GateContextItem,
-    gate_configuration: &input::InputCartLinesMerchandiseOnProductVariantProductGatesConfiguration,
+    gate_configuration_id: &str,
+    requirements: Option<
+        &input::InputCartLinesMerchandiseOnProductVariantProductGatesConfigurationRequirements,
+    >,
+    verification_context: &VerificationContext,
 ) -> bool {
-    let hmac = match &gate_context_item.hmac {
-        Some(hmac) => hmac,
-        _ => return false,
+    // An item is either for a single gate configuration or an aggregate. Token
+    // counts are per gate, so aggregates cannot carry one.
+    if gate_context_item.ids.is_some()
+        && (gate_context_item.id.is_some() || gate_context_item.token_count.is_some())
+    {
+        return false;
+    }
+
+    let required_binding = verification_context.configuration.buyer_binding;
+    if required_binding != BuyerBinding::None && gate_context_item.binding != Some(required_binding)
+    {
+        return false;
+    }
+
+    // Items proving a customer is in a Merkle tree are bound to the buyer instead
+    if gate_context_item.address.is_none()
+        && gate_context_item.proof.is_none()
+        && !verification_context
+            .configuration
+            .allow_contexts_without_address
+    {
+        return false;
+    }
+
+    if !is_within_validity_period(gate_context_item, verification_context) {
+        return false;
+    }
+
+    if let Some(nonce) = &gate_context_item.nonce {
+        if verification_context.revocation_list.is_revoked(nonce) {
+            eprintln!("Gate context with nonce {} is revoked", nonce);
+            return false;
+        }
+    }
+
+    let message = match signed_message(
+        gate_context_item,
+        gate_configuration_id,
+        verification_context.buyer_identity,
+    ) {
+        Some(message) => message,
+        None => return false,
     };
 
-    let message = &gate_configuration.id;
-    let signature = hmac_signature(SECRET_KEY, message);
+    // Each item is signed with exactly one scheme. Token counts are only trusted when
+    // the app signs them, as wallets would vouch for their own and Merkle proofs for
+    // customers are not signed at all.
+    let without_token_count = gate_context_item.token_count.is_none();
+    match (
+        &gate_context_item.hmac,
+        &gate_context_item.ed25519,
+        &gate_context_item.personal_sign,
+        &gate_context_item.proof,
+    ) {
+        (Some(hmac), None, None, None) => match verification_context.keyring {
+            Some(keyring) => is_hmac_valid(
+                hmac,
+                &message,
+                gate_configuration_id,
+                gate_context_item,
+                keyring,
+            ),
+            None => false,
+        },
+        (None, Some(signature), None, None) => match verification_context.public_key {
+            Some(public_key) => is_ed25519_signature_valid(signature, &message, public_key),
+            None => false,
+        },
+        (None, None, Some(signature), None) if without_token_count => {
+            let requirements = match gate_requirements(gate_configuration_id, requirements) {
+                Some(requirements) => requirements,
+                None => return false,
+            };
+            is_personal_sign_valid(
+                signature,
+                &message,
+                gate_context_item.address.as_deref(),
+                &requirements,
+            )
+        }
+        (None, None, signature, Some(proof)) if without_token_count => {
+            let requirements = match gate_requirements(gate_configuration_id, requirements) {
+                Some(requirements) => requirements,
+                None => return false,
+            };
+            is_merkle_proof_valid(
+                gate_context_item,
+                proof,
+                signature.as_deref(),
+                &message,
+                &requirements,
+                verification_context.buyer_identity,
+            )
+        }
+        _ => false,
+    }
+}
 
-    &signature == hmac
+// Malformed requirements keep the gate locked, as nothing can be checked against them
+fn gate_requirements(
+    gate_configuration_id: &str,
+    metafield: Option<
+        &input::InputCartLinesMerchandiseOnProductVariantProductGatesConfigurationRequirements,
+    >,
+) -> Option<GateRequirements> {
+    match parse_gate_requirements_from_metafield(metafield) {
+        Ok(requirements) => Some(requirements),
+        Err(error) => {
+            eprintln!("Locking {}: {}", gate_configuration_id, error);
+            None
+        }
+    }
 }
 
-fn hmac_signature(key: &str, msg: &str) -> String {
-    type HmacSha256 = Hmac<Sha256>;
+fn is_hmac_valid(
+    hmac: &str,
+    message: &str,
+    gate_configuration_id: &str,
+    gate_context_item: &GateContextItem,
+    keyring: &Keyring,
+) -> bool {
+    // Compare raw bytes rather than hex strings, so the comparison is constant-time
+    // and does not depend on the case of the hex digits
+    let signature = match decode_hex("hmac", hmac) {
+        Ok(signature) => signature,
+        Err(error) => {
+            eprintln!("Rejected gate context item: {}", error);
+            return false;
+        }
+    };
 
-    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).unwrap();
-    mac.update(&msg.as_bytes());
+    keyring
+        .verification_keys(gate_context_item.kid.as_deref())
+        .into_iter()
+        .any(|key| {
+            let mac = match key.derivation {
+                KeyDerivation::Direct => hmac_signature(key.secret.as_bytes(), message),
+                // Aggregate items span gates, so no single gate key can verify them
+                KeyDerivation::Hkdf if gate_context_item.ids.is_some() => return false,
+                KeyDerivation::Hkdf => {
+                    hmac_signature(&gate_key(&key.secret, gate_configuration_id), message)
+                }
+            };
+            #[cfg(feature = "compact")]
+            if gate_context_item.hmac_truncated {
+                return signature.len() == compact::TRUNCATED_HMAC_LENGTH
+                    && bool::from(
+                        mac.finalize().into_bytes()[..compact::TRUNCATED_HMAC_LENGTH]
+                            .ct_eq(&signature),
+                    );
+            }
+            mac.verify(&signature).is_ok()
+        })
+}
 
-    let code_bytes = mac.finalize().into_bytes();
+#[cfg(feature = "ed25519")]
+fn is_ed25519_signature_valid(signature: &str, message: &str, public_key: &PublicKey) -> bool {
+    let bytes = match decode_hex("ed25519", signature) {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            eprintln!("Rejected gate context item: {}", error);
+            return false;
+        }
+    };
+    let signature = match Signature::from_slice(&bytes) {
+        Ok(signature) => signature,
+        Err(_) => {
+            eprintln!("Rejected gate context item: ed25519 is not a signature");
+            return false;
+        }
+    };
 
-    return hex::encode(&code_bytes.to_vec());
+    public_key.verify(message, &signature).is_ok()
 }
 
-fn reaction_value(reaction: GateReaction) -> output::Value {
-    match reaction.discount {
-        Discount::Percentage { value } => {
-            return output::Value::Percentage(output::Percentage {
-                value: value.to_string(),
-            });
+#[cfg(not(feature = "ed25519"))]
+fn is_ed25519_signature_valid(_signature: &str, _message: &str, public_key: &PublicKey) -> bool {
+    match *public_key {}
+}
+
+// The wallet signs the same message the app would sign, and has to be both the
+// wallet named by the item and one of the wallets on the gate's allowlist
+fn is_personal_sign_valid(
+    signature: &str,
+    message: &str,
+    address: Option<&str>,
+    requirements: &GateRequirements,
+) -> bool {
+    let address = match address.and_then(normalized_wallet_address) {
+        Some(address) => address,
+        None => return false,
+    };
+    let allowlisted = requirements
+        .allowlist
+        .iter()
+        .filter_map(|allowlisted| normalized_wallet_address(allowlisted))
+        .any(|allowlisted| allowlisted == address);
+
+    allowlisted && recover_personal_sign_address(signature, message) == Some(address)
+}
+
+#[cfg(feature = "personal-sign")]
+fn recover_personal_sign_address(signature: &str, message: &str) -> Option<String> {
+    let bytes = match decode_hex(
+        "personalSign",
+        signature.strip_prefix("0x").unwrap_or(signature),
+    ) {
+        Ok(bytes) if bytes.len() == 65 => bytes,
+        Ok(_) => {
+            eprintln!("Rejected gate context item: personalSign is not 65 bytes");
+            return None;
         }
-        Discount::Amount { value } => {
-            return output::Value::FixedAmount(output::FixedAmount {
-                applies_to_each_item: None,
-                amount: value.to_string(),
-            });
+        Err(error) => {
+            eprintln!("Rejected gate context item: {}", error);
+            return None;
+        }
+    };
+
+    // Wallets use 27 and 28 as recovery IDs, most libraries 0 and 1
+    let recovery_id = match bytes[64] {
+        27 | 28 => RecoveryId::from_byte(bytes[64] - 27)?,
+        byte => RecoveryId::from_byte(byte)?,
+    };
+    let signature = k256::ecdsa::Signature::from_slice(&bytes[..64]).ok()?;
+
+    let prefixed_message = format!("\x19Ethereum Signed Message:\n{}{}", message.len(), message);
+    let digest = Keccak256::digest(prefixed_message.as_bytes());
+    let verifying_key =
+        VerifyingKey::recover_from_prehash(&digest, &signature, recovery_id).ok()?;
+
+    // The address is the last 20 bytes of the hash of the uncompressed public key,
+    // without its leading 0x04 tag
+    let public_key = verifying_key.to_encoded_point(false);
+    let public_key_hash = Keccak256::digest(&public_key.as_bytes()[1..]);
+    Some(format!("0x{}", hex::encode(&public_key_hash[12..])))
+}
+
+#[cfg(not(feature = "personal-sign"))]
+fn recover_personal_sign_address(_signature: &str, _message: &str) -> Option<String> {
+    eprintln!(
+        "Rejected gate context item: the function is built without the personal-sign feature"
+    );
+    None
+}
+
+// Proofs are public, so the leaf also has to be the buyer: either the wallet that
+// signed the gate context, or the customer of the cart
+fn is_merkle_proof_valid(
+    gate_context_item: &GateContextItem,
+    proof: &[String],
+    signature: Option<&str>,
+    message: &str,
+    requirements: &GateRequirements,
+    buyer_identity: Option<&input::InputCartBuyerIdentity>,
+) -> bool {
+    let root = match requirements
+        .merkle_root
+        .as_ref()
+        .map(|root| decode_merkle_hash("merkleRoot", root))
+    {
+        Some(Ok(root)) => root,
+        Some(Err(error)) => {
+            eprintln!("Rejected gate requirements: {}", error);
+            return false;
+        }
+        None => return false,
+    };
+    let leaf = match gate_context_item
+        .leaf
+        .as_deref()
+        .and_then(|leaf| normalized_merkle_leaf(leaf, requirements.merkle_leaves))
+    {
+        Some(leaf) => leaf,
+        None => return false,
+    };
+
+    let leaf_is_buyer = match requirements.merkle_leaves {
+        MerkleLeaves::Address => match (signature, &gate_context_item.address) {
+            (Some(signature), Some(address)) => {
+                normalized_wallet_address(address).as_deref() == Some(leaf.as_str())
+                    && recover_personal_sign_address(signature, message).as_deref()
+                        == Some(leaf.as_str())
+            }
+            _ => false,
+        },
+        MerkleLeaves::Customer => {
+            let customer = buyer_identity.and_then(|buyer| buyer.customer.as_ref());
+            signature.is_none() && customer.map(|customer| customer.id.as_str()) == Some(&leaf)
+        }
+    };
+    if !leaf_is_buyer {
+        return false;
+    }
+
+    let proof: std::result::Result<Vec<merkle::Hash>, GateError> = proof
+        .iter()
+        .map(|hash| decode_merkle_hash("proof", hash))
+        .collect();
+    match proof {
+        Ok(proof) => merkle::verify_proof(&root, &leaf, &proof),
+        Err(error) => {
+            eprintln!("Rejected gate context item: {}", error);
+            false
+        }
+    }
+}
+
+// Hashes of the wrong length are as unusable as ones that are not hex at all
+fn decode_merkle_hash(
+    field: &'static str,
+    hash: &str,
+) -> std::result::Result<merkle::Hash, GateError> {
+    decode_hex(field, hash.strip_prefix("0x").unwrap_or(hash))?
+        .try_into()
+        .map_err(|_| GateError::BadHex(field))
+}
+
+fn normalized_merkle_leaf(leaf: &str, leaves: MerkleLeaves) -> Option<String> {
+    match leaves {
+        MerkleLeaves::Address => normalized_wallet_address(leaf),
+        MerkleLeaves::Customer if leaf.starts_with("gid://shopify/Customer/") => {
+            Some(leaf.to_string())
+        }
+        MerkleLeaves::Customer => None,
+    }
+}
+
+// Version 1 messages are the bare gate configuration ID. Version 2 messages are
+// `key=value` lines after a `v2` header, so that more signed fields can be added
+// without two different items ever producing the same message. Aggregate items
+// sign the sorted and deduplicated gate configuration IDs as one `ids=` line
+// instead of the `id=` line. Returns `None` when the item cannot be verified
+// against this cart, e.g. it is bound to a customer and the buyer is not logged in.
+fn signed_message(
+    gate_context_item: &GateContextItem,
+    gate_configuration_id: &str,
+    buyer_identity: Option<&input::InputCartBuyerIdentity>,
+) -> Option<String> {
+    let binding = gate_context_item.binding.unwrap_or_default();
+
+    match gate_context_item.v.unwrap_or(1) {
+        1 if binding == BuyerBinding::None
+            && gate_context_item.ids.is_none()
+            && gate_context_item.nonce.is_none()
+            && gate_context_item.token_count.is_none()
+            && gate_context_item.address.is_none()
+            && gate_context_item.issued_at.is_none()
+            && gate_context_item.expires_at.is_none() =>
+        {
+            Some(gate_configuration_id.to_string())
         }
+        2 => {
+            let subject = match &gate_context_item.ids {
+                Some(ids) => {
+                    let mut ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+                    ids.sort_unstable();
+                    ids.dedup();
+                    format!("ids={}", ids.join(","))
+                }
+                None => format!("id={}", gate_configuration_id),
+            };
+            let mut lines = vec!["v2".to_string(), subject];
+            if let Some(address) = &gate_context_item.address {
+                lines.push(format!("address={}", normalized_wallet_address(address)?));
+            }
+            if let Some(issued_at) = gate_context_item.issued_at {
+                lines.push(format!("issuedAt={}", issued_at));
+            }
+            if let Some(expires_at) = gate_context_item.expires_at {
+                lines.push(format!("expiresAt={}", expires_at));
+            }
+            if let Some(nonce) = &gate_context_item.nonce {
+                lines.push(format!("nonce={}", valid_nonce(nonce)?));
+            }
+            if let Some(token_count) = gate_context_item.token_count {
+                lines.push(format!("tokenCount={}", token_count));
+            }
+            match binding {
+                BuyerBinding::None => {}
+                BuyerBinding::Customer => {
+                    let customer = buyer_identity?.customer.as_ref()?;
+                    lines.push(format!("customer={}", customer.id));
+                }
+                BuyerBinding::Email => {
+                    // Only ASCII letters are lowercased, as Unicode case tables would grow the module
+                    let email = buyer_identity?.email.as_ref()?.trim().to_ascii_lowercase();
+                    lines.push(format!("email={}", email));
+                }
+            }
+            Some(lines.join("\n"))
+        }
+        _ => None,
+    }
+}
+
+// Expired items are locked. Items that expire cannot be checked without a
+// reference time, so they are locked as well when it is missing.
+fn is_within_validity_period(
+    gate_context_item: &GateContextItem,
+    verification_context: &VerificationContext,
+) -> bool {
+    if gate_context_item.issued_at.is_none() && gate_context_item.expires_at.is_none() {
+        return true;
+    }
+
+    let reference_time = match verification_context.reference_time {
+        Some(reference_time) => reference_time,
+        None => return false,
     };
+    let clock_skew = verification_context.configuration.clock_skew_seconds;
+
+    if let Some(issued_at) = gate_context_item.issued_at {
+        if issued_at > reference_time.saturating_add(clock_skew) {
+            return false;
+        }
+    }
+    if let Some(expires_at) = gate_context_item.expires_at {
+        if expires_at.saturating_add(clock_skew) < reference_time {
+            return false;
+        }
+    }
+
+    true
+}
+
+// Nonces are signed as a line of their own, so they cannot contain a line break or
+// anything else that could pass for another signed field
+fn valid_nonce(nonce: &str) -> Option<&str> {
+    let valid = !nonce.is_empty()
+        && nonce.len() <= 64
+        && nonce
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    valid.then_some(nonce)
 }
+
+// Checksummed and lowercase addresses refer to the same wallet, so addresses are
+// signed lowercase
+fn normalized_wallet_address(address: &str) -> Option<String> {
+    let hex_digits = address.strip_prefix("0x")?;
+    if hex_digits.len() == 40 && hex_digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(address.to_ascii_lowercase())
+    } else {
+        None
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Salt for deriving gate keys, which must never change for as long as keys derived
+// with it are in use
+const GATE_KEY_SALT: &[u8] = b"tokengating-example-app/gate-key";
+
+// A key that only verifies the contexts of one gate configuration, so that a
+// leaked gate key cannot mint contexts for any other gate
+fn gate_key(secret: &str, gate_configuration_id: &str) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(GATE_KEY_SALT), secret.as_bytes())
+        .expand(gate_configuration_id.as_bytes(), &mut key)
+        .unwrap();
+
+    key
+}
+
+fn hmac_signature(key: &[u8], msg: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(msg.as_bytes());
+
+    mac
+}
+
+fn reaction_value(reaction: LineReaction) -> output::Value {
+    match reaction.discount {
+        LineDiscount::Percentage { value } => output::Value::Percentage(output::Percentage {
+            value: value.to_string(),
+        }),
+        LineDiscount::Amount {
+            value,
+            applies_to_each_item,
+        } => output::Value::FixedAmount(output::FixedAmount {
+            applies_to_each_item,
+            amount: value.to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests;
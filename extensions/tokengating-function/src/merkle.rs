@@ -0,0 +1,99 @@
+// Merkle trees over the wallet addresses or customer IDs that may unlock a gate.
+// Leaves and nodes are hashed with different prefixes, so that a node can never
+// pass for a leaf, and the two children of a node are hashed in sorted order, so
+// that proofs do not need to record on which side each sibling sits.
+use sha2::{Digest, Sha256};
+
+pub type Hash = [u8; 32];
+
+// Enough for far more leaves than a metafield-driven drop will ever have, and
+// bounds the work a forged proof can cause
+const MAX_PROOF_LENGTH: usize = 32;
+
+pub fn leaf_hash(leaf: &str) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([0u8]);
+    hasher.update(leaf.as_bytes());
+    hasher.finalize().into()
+}
+
+fn node_hash(a: &Hash, b: &Hash) -> Hash {
+    let (left, right) = if a <= b { (a, b) } else { (b, a) };
+    let mut hasher = Sha256::new();
+    hasher.update([1u8]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+pub fn verify_proof(root: &Hash, leaf: &str, proof: &[Hash]) -> bool {
+    if proof.len() > MAX_PROOF_LENGTH {
+        return false;
+    }
+
+    let computed_root = proof
+        .iter()
+        .fold(leaf_hash(leaf), |hash, sibling| node_hash(&hash, sibling));
+    &computed_root == root
+}
+
+// Builds roots and proofs for fixtures. Only the first column of each CSV row is
+// used, and rows that are not a valid leaf, like a header, are skipped.
+#[cfg(test)]
+pub struct MerkleTree {
+    levels: Vec<Vec<Hash>>,
+}
+
+#[cfg(test)]
+impl MerkleTree {
+    pub fn from_csv(csv: &str, leaves: crate::MerkleLeaves) -> MerkleTree {
+        let mut hashes: Vec<Hash> = csv
+            .lines()
+            .filter_map(|row| row.split(',').next())
+            .filter_map(|leaf| crate::normalized_merkle_leaf(leaf.trim(), leaves))
+            .map(|leaf| leaf_hash(&leaf))
+            .collect();
+        hashes.sort_unstable();
+        hashes.dedup();
+
+        let mut levels = vec![hashes];
+        while levels.last().unwrap().len() > 1 {
+            // An odd node out is carried up to the next level unchanged
+            let level = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [a, b] => node_hash(a, b),
+                    [a] => *a,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(level);
+        }
+
+        MerkleTree { levels }
+    }
+
+    pub fn root(&self) -> Hash {
+        self.levels
+            .last()
+            .unwrap()
+            .first()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn proof(&self, leaf: &str) -> Option<Vec<Hash>> {
+        let mut index = self.levels[0].binary_search(&leaf_hash(leaf)).ok()?;
+        let mut proof = vec![];
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(index ^ 1) {
+                proof.push(*sibling);
+            }
+            index /= 2;
+        }
+
+        Some(proof)
+    }
+}
@@ -0,0 +1,116 @@
+// Exact arithmetic on the decimal amounts of the input, for reactions that have to
+// work out an amount themselves. Amounts are scaled to integers, so that a target
+// price converted to the currency of the cart never ends up a fraction of a minor
+// unit off.
+use crate::input::CurrencyCode;
+
+// Enough digits for any amount or conversion rate Shopify passes to the function
+const SCALE: u32 = 9;
+
+// The number of decimals of the minor unit of the currency, per ISO 4217
+pub fn currency_decimals(currency_code: &CurrencyCode) -> u32 {
+    match currency_code {
+        CurrencyCode::BIF
+        | CurrencyCode::CLP
+        | CurrencyCode::DJF
+        | CurrencyCode::GNF
+        | CurrencyCode::ISK
+        | CurrencyCode::JPY
+        | CurrencyCode::KMF
+        | CurrencyCode::KRW
+        | CurrencyCode::PYG
+        | CurrencyCode::RWF
+        | CurrencyCode::UGX
+        | CurrencyCode::VND
+        | CurrencyCode::VUV
+        | CurrencyCode::XAF
+        | CurrencyCode::XOF
+        | CurrencyCode::XPF => 0,
+        CurrencyCode::BHD
+        | CurrencyCode::IQD
+        | CurrencyCode::JOD
+        | CurrencyCode::KWD
+        | CurrencyCode::LYD
+        | CurrencyCode::OMR
+        | CurrencyCode::TND => 3,
+        _ => 2,
+    }
+}
+
+// What has to come off each unit priced at `unit_price` in the currency of the
+// cart for it to cost `target_price` in the currency of the shop, rounded to the
+// minor unit of the cart's currency and never below zero
+pub fn amount_off_to_reach(
+    unit_price: &str,
+    target_price: &str,
+    presentment_currency_rate: &str,
+    decimals: u32,
+) -> Option<String> {
+    let unit_price = parse_decimal(unit_price)?;
+    let target_price = parse_decimal(target_price)?;
+    let presentment_currency_rate = parse_decimal(presentment_currency_rate)?;
+
+    let unit_price = round(unit_price, SCALE - decimals);
+    let target_price = round(
+        target_price.checked_mul(presentment_currency_rate)?,
+        2 * SCALE - decimals,
+    );
+
+    Some(format_decimal((unit_price - target_price).max(0), decimals))
+}
+
+// `percentage` percent of `amount`, both scaled by 10^SCALE, and never more than
+// all of it
+pub fn percentage_of(amount: i128, percentage: i128) -> i128 {
+    let hundred_percent = 100 * 10i128.pow(SCALE);
+    amount.saturating_mul(percentage.min(hundred_percent)) / hundred_percent
+}
+
+pub fn is_zero(amount: &str) -> bool {
+    parse_decimal(amount) == Some(0)
+}
+
+// Parses a non-negative decimal like `19.99` or `20` into an integer scaled by
+// 10^SCALE. Digits beyond the scale are rounded half up.
+pub fn parse_decimal(value: &str) -> Option<i128> {
+    let value = value.trim();
+    let (integer, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if integer.is_empty()
+        || !integer.bytes().all(|byte| byte.is_ascii_digit())
+        || !fraction.bytes().all(|byte| byte.is_ascii_digit())
+        || integer.len() > 18
+    {
+        return None;
+    }
+
+    let mut scaled: i128 = integer.parse().ok()?;
+    let mut digits = fraction.bytes().map(|byte| i128::from(byte - b'0'));
+    for _ in 0..SCALE {
+        scaled = scaled * 10 + digits.next().unwrap_or(0);
+    }
+    if digits.next().unwrap_or(0) >= 5 {
+        scaled += 1;
+    }
+
+    Some(scaled)
+}
+
+// Rounds half up to a multiple of 10^digits, and drops those digits
+fn round(value: i128, digits: u32) -> i128 {
+    let unit = 10i128.pow(digits);
+    (value + unit / 2) / unit
+}
+
+fn format_decimal(value: i128, decimals: u32) -> String {
+    if decimals == 0 {
+        return value.to_string();
+    }
+
+    let unit = 10i128.pow(decimals);
+    format!(
+        "{}.{:0width$}",
+        value / unit,
+        value % unit,
+        width = decimals as usize
+    )
+}
@@ -14,6 +14,12 @@ fn input(
             "lines": [
                 {
                     "quantity": 1,
+                    "cost": {
+                        "amountPerQuantity": {
+                            "amount": "100.0",
+                            "currencyCode": "USD"
+                        }
+                    },
                     "merchandise": {
                         "__typename": "ProductVariant",
                         "id": "gid://shopify/ProductVariant/1",
@@ -27,6 +33,9 @@ fn input(
                                         "handle": "tokengating-example-app",
                                         "metafield": {
                                             "value": "{\"name\":\"Snowdevil discount\",\"type\":\"discount\",\"discount\":{\"type\":\"percentage\",\"value\": 25}}"
+                                        },
+                                        "requirements": {
+                                            "value": "{\"allowlist\":[\"0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf\"]}"
                                         }
                                     }
                                 }
@@ -36,6 +45,12 @@ fn input(
                 },
                 {
                     "quantity": 1,
+                    "cost": {
+                        "amountPerQuantity": {
+                            "amount": "20.0",
+                            "currencyCode": "USD"
+                        }
+                    },
                     "merchandise": {
                         "__typename": "ProductVariant",
                         "id": "gid://shopify/ProductVariant/2",
@@ -58,6 +73,12 @@ fn input(
                 },
                 {
                     "quantity": 1,
+                    "cost": {
+                        "amountPerQuantity": {
+                            "amount": "30.0",
+                            "currencyCode": "USD"
+                        }
+                    },
                     "merchandise": {
                         "__typename": "ProductVariant",
                         "id": "gid://shopify/ProductVariant/3",
@@ -80,6 +101,12 @@ fn input(
                 },
                 {
                     "quantity": 1,
+                    "cost": {
+                        "amountPerQuantity": {
+                            "amount": "40.0",
+                            "currencyCode": "USD"
+                        }
+                    },
                     "merchandise": {
                         "__typename": "ProductVariant",
                         "id": "gid://shopify/ProductVariant/4",
@@ -105,8 +132,15 @@ fn input(
         "discountNode": {
             "metafield": {
                 "value": ""
+            },
+            "secretKey": {
+                "value": "secret-key"
+            },
+            "configuration": {
+                "value": "{\"allowContextsWithoutAddress\": true}"
             }
-        }
+        },
+        "presentmentCurrencyRate": "1.0"
     }
     "#;
     let mut input: input::ResponseData = serde_json::from_str(default_input).unwrap();
@@ -146,18 +180,15 @@ fn test_discount_as_percentage_with_valid_gate_context() -> Result<()> {
     let expected = crate::output::FunctionResult {
         discounts: vec![crate::output::Discount {
             message: Some("Snowdevil discount".to_string()),
-            targets: vec![crate::output::Target {
-                product_variant: Some(crate::output::ProductVariantTarget {
+            targets: vec![crate::output::Target::ProductVariant(
+                crate::output::ProductVariantTarget {
                     id: "gid://shopify/ProductVariant/1".to_string(),
                     quantity: None,
-                }),
-            }],
-            value: crate::output::Value {
-                fixed_amount: None,
-                percentage: Some(crate::output::Percentage {
-                    value: "25".to_string(),
-                }),
-            },
+                },
+            )],
+            value: crate::output::Value::Percentage(crate::output::Percentage {
+                value: "25".to_string(),
+            }),
         }],
         discount_application_strategy: crate::output::DiscountApplicationStrategy::MAXIMUM,
     };
@@ -195,26 +226,19 @@ fn test_discount_on_two_products_with_valid_gate_context() -> Result<()> {
         discounts: vec![crate::output::Discount {
             message: Some("Tokenfolk discount".to_string()),
             targets: vec![
-                crate::output::Target {
-                    product_variant: Some(crate::output::ProductVariantTarget {
-                        id: "gid://shopify/ProductVariant/2".to_string(),
-                        quantity: None,
-                    }),
-                },
-                crate::output::Target {
-                    product_variant: Some(crate::output::ProductVariantTarget {
-                        id: "gid://shopify/ProductVariant/3".to_string(),
-                        quantity: None,
-                    }),
-                },
-            ],
-            value: crate::output::Value {
-                percentage: None,
-                fixed_amount: Some(crate::output::FixedAmount {
-                    amount: "10".to_string(),
-                    applies_to_each_item: None,
+                crate::output::Target::ProductVariant(crate::output::ProductVariantTarget {
+                    id: "gid://shopify/ProductVariant/2".to_string(),
+                    quantity: None,
                 }),
-            },
+                crate::output::Target::ProductVariant(crate::output::ProductVariantTarget {
+                    id: "gid://shopify/ProductVariant/3".to_string(),
+                    quantity: None,
+                }),
+            ],
+            value: crate::output::Value::FixedAmount(crate::output::FixedAmount {
+                amount: "10".to_string(),
+                applies_to_each_item: None,
+            }),
         }],
         discount_application_strategy: crate::output::DiscountApplicationStrategy::MAXIMUM,
     };
@@ -319,3 +343,2831 @@ fn test_discount_with_null_gate_context() -> Result<()> {
     assert_eq!(result, expected);
     Ok(())
 }
+
+fn valid_gate_context() -> Option<input::InputCartAttribute> {
+    Some(input::InputCartAttribute {
+        value: Some(
+            r#"
+                [
+                  {
+                    "id": "gid://shopify/GateConfiguration/1",
+                    "hmac": "bd3862797c3e532f9f07e6672192d46792ee3591a0c7fe279e14d971eb541b37"
+                  }
+                ]
+            "#
+            .to_string(),
+        ),
+    })
+}
+
+fn discount_gate_configuration_id() -> Option<input::InputDiscountNodeMetafield> {
+    Some(input::InputDiscountNodeMetafield {
+        value: "gid://shopify/GateConfiguration/1".to_string(),
+    })
+}
+
+#[test]
+fn test_discount_with_missing_secret_key() -> Result<()> {
+    let mut input = input(valid_gate_context(), discount_gate_configuration_id());
+    input.discount_node.secret_key = None;
+
+    let result = function(input)?;
+    let expected = crate::output::FunctionResult {
+        discounts: vec![],
+        discount_application_strategy: crate::output::DiscountApplicationStrategy::MAXIMUM,
+    };
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_empty_secret_key() -> Result<()> {
+    let mut input = input(valid_gate_context(), discount_gate_configuration_id());
+    input.discount_node.secret_key = Some(input::InputDiscountNodeSecretKey {
+        value: "  ".to_string(),
+    });
+
+    let result = function(input)?;
+    let expected = crate::output::FunctionResult {
+        discounts: vec![],
+        discount_application_strategy: crate::output::DiscountApplicationStrategy::MAXIMUM,
+    };
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_gate_context_signed_by_another_secret_key() -> Result<()> {
+    let mut input = input(valid_gate_context(), discount_gate_configuration_id());
+    input.discount_node.secret_key = Some(input::InputDiscountNodeSecretKey {
+        value: "another-secret-key".to_string(),
+    });
+
+    let result = function(input)?;
+    let expected = crate::output::FunctionResult {
+        discounts: vec![],
+        discount_application_strategy: crate::output::DiscountApplicationStrategy::MAXIMUM,
+    };
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+fn gate_context_with_kid(kid: Option<&str>, hmac: &str) -> Option<input::InputCartAttribute> {
+    let mut item = serde_json::json!({
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": hmac,
+    });
+    if let Some(kid) = kid {
+        item["kid"] = serde_json::json!(kid);
+    }
+
+    Some(input::InputCartAttribute {
+        value: Some(serde_json::json!([item]).to_string()),
+    })
+}
+
+fn keyring_secret_key() -> Option<input::InputDiscountNodeSecretKey> {
+    Some(input::InputDiscountNodeSecretKey {
+        value: r#"
+            {
+              "keys": [
+                { "kid": "2023-10", "secret": "new-secret-key", "status": "active" },
+                { "kid": "2023-04", "secret": "old-secret-key", "status": "retiring" },
+                { "kid": "2022-10", "secret": "revoked-secret-key", "status": "revoked" }
+              ]
+            }
+        "#
+        .to_string(),
+    })
+}
+
+fn snowdevil_discount() -> crate::output::FunctionResult {
+    crate::output::FunctionResult {
+        discounts: vec![crate::output::Discount {
+            message: Some("Snowdevil discount".to_string()),
+            targets: vec![crate::output::Target::ProductVariant(
+                crate::output::ProductVariantTarget {
+                    id: "gid://shopify/ProductVariant/1".to_string(),
+                    quantity: None,
+                },
+            )],
+            value: crate::output::Value::Percentage(crate::output::Percentage {
+                value: "25".to_string(),
+            }),
+        }],
+        discount_application_strategy: crate::output::DiscountApplicationStrategy::MAXIMUM,
+    }
+}
+
+fn no_discount() -> crate::output::FunctionResult {
+    crate::output::FunctionResult {
+        discounts: vec![],
+        discount_application_strategy: crate::output::DiscountApplicationStrategy::MAXIMUM,
+    }
+}
+
+#[test]
+fn test_discount_with_gate_context_signed_by_new_key() -> Result<()> {
+    let attribute = gate_context_with_kid(
+        Some("2023-10"),
+        "d76f2d1cfe7530187fac1e940ecffd9cfa7e3a1c490eb24fce7f896f69ee6b77",
+    );
+    let mut input = input(attribute, discount_gate_configuration_id());
+    input.discount_node.secret_key = keyring_secret_key();
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_gate_context_signed_by_retiring_key() -> Result<()> {
+    let attribute = gate_context_with_kid(
+        Some("2023-04"),
+        "7dad4c33b5a433b114bc403855e54f7edd663b94c00ff9be074b963a51be56f3",
+    );
+    let mut input = input(attribute, discount_gate_configuration_id());
+    input.discount_node.secret_key = keyring_secret_key();
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_gate_context_signed_by_revoked_key() -> Result<()> {
+    let attribute = gate_context_with_kid(
+        Some("2022-10"),
+        "402983ec44bc39c64ce8ff0dbd713fdb78393d3333a47c868460f0742e705551",
+    );
+    let mut input = input(attribute, discount_gate_configuration_id());
+    input.discount_node.secret_key = keyring_secret_key();
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_gate_context_signed_by_unknown_kid() -> Result<()> {
+    let attribute = gate_context_with_kid(
+        Some("2024-01"),
+        "d76f2d1cfe7530187fac1e940ecffd9cfa7e3a1c490eb24fce7f896f69ee6b77",
+    );
+    let mut input = input(attribute, discount_gate_configuration_id());
+    input.discount_node.secret_key = keyring_secret_key();
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_without_kid_only_tries_active_keys() -> Result<()> {
+    let mut signed_by_active_key = input(
+        gate_context_with_kid(
+            None,
+            "d76f2d1cfe7530187fac1e940ecffd9cfa7e3a1c490eb24fce7f896f69ee6b77",
+        ),
+        discount_gate_configuration_id(),
+    );
+    signed_by_active_key.discount_node.secret_key = keyring_secret_key();
+    assert_eq!(function(signed_by_active_key)?, snowdevil_discount());
+
+    let mut signed_by_retiring_key = input(
+        gate_context_with_kid(
+            None,
+            "7dad4c33b5a433b114bc403855e54f7edd663b94c00ff9be074b963a51be56f3",
+        ),
+        discount_gate_configuration_id(),
+    );
+    signed_by_retiring_key.discount_node.secret_key = keyring_secret_key();
+    assert_eq!(function(signed_by_retiring_key)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_uppercase_hmac_in_gate_context() -> Result<()> {
+    let attribute = gate_context_with_kid(
+        None,
+        "BD3862797C3E532F9F07E6672192D46792EE3591A0C7FE279E14D971EB541B37",
+    );
+
+    let result = function(input(attribute, discount_gate_configuration_id()))?;
+    assert_eq!(result, snowdevil_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_odd_length_hmac_in_gate_context() -> Result<()> {
+    let attribute = gate_context_with_kid(
+        None,
+        "bd3862797c3e532f9f07e6672192d46792ee3591a0c7fe279e14d971eb541b3",
+    );
+
+    let result = function(input(attribute, discount_gate_configuration_id()))?;
+    assert_eq!(result, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_non_hex_hmac_in_gate_context() -> Result<()> {
+    let attribute = gate_context_with_kid(
+        None,
+        "zz3862797c3e532f9f07e6672192d46792ee3591a0c7fe279e14d971eb541b37",
+    );
+
+    let result = function(input(attribute, discount_gate_configuration_id()))?;
+    assert_eq!(result, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_truncated_hmac_in_gate_context() -> Result<()> {
+    let attribute = gate_context_with_kid(None, "bd3862797c3e532f");
+
+    let result = function(input(attribute, discount_gate_configuration_id()))?;
+    assert_eq!(result, no_discount());
+    Ok(())
+}
+
+fn gate_context_from_json(items: serde_json::Value) -> Option<input::InputCartAttribute> {
+    Some(input::InputCartAttribute {
+        value: Some(items.to_string()),
+    })
+}
+
+fn buyer_identity(
+    customer_id: Option<&str>,
+    email: Option<&str>,
+) -> Option<input::InputCartBuyerIdentity> {
+    Some(input::InputCartBuyerIdentity {
+        email: email.map(|email| email.to_string()),
+        customer: customer_id
+            .map(|id| input::InputCartBuyerIdentityCustomer { id: id.to_string() }),
+    })
+}
+
+fn configuration(value: &str) -> Option<input::InputDiscountNodeConfiguration> {
+    Some(input::InputDiscountNodeConfiguration {
+        value: value.to_string(),
+    })
+}
+
+fn customer_bound_gate_context() -> Option<input::InputCartAttribute> {
+    gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "e0c3f8b0e7d254f0ac2b3e5ba3a46f504013e99cc4c5dba79185effb9ab0cfe5",
+        "v": 2,
+        "binding": "customer"
+    }]))
+}
+
+#[test]
+fn test_discount_with_gate_context_bound_to_buyer() -> Result<()> {
+    let mut input = input(
+        customer_bound_gate_context(),
+        discount_gate_configuration_id(),
+    );
+    input.cart.buyer_identity = buyer_identity(Some("gid://shopify/Customer/1"), None);
+    input.discount_node.configuration =
+        configuration(r#"{"buyerBinding": "customer", "allowContextsWithoutAddress": true}"#);
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_gate_context_bound_to_another_buyer() -> Result<()> {
+    let mut input = input(
+        customer_bound_gate_context(),
+        discount_gate_configuration_id(),
+    );
+    input.cart.buyer_identity = buyer_identity(Some("gid://shopify/Customer/2"), None);
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_gate_context_bound_to_buyer_and_anonymous_buyer() -> Result<()> {
+    let input = input(
+        customer_bound_gate_context(),
+        discount_gate_configuration_id(),
+    );
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_gate_context_bound_to_buyer_email() -> Result<()> {
+    let attribute = gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "37a8d4f9e1b00a5a239228d225516b578f7ff55ea48339e841e4ba181dbe75c5",
+        "v": 2,
+        "binding": "email"
+    }]));
+    let mut input = input(attribute, discount_gate_configuration_id());
+    input.cart.buyer_identity = buyer_identity(None, Some(" Holder@Example.com "));
+    input.discount_node.configuration =
+        configuration(r#"{"buyerBinding": "email", "allowContextsWithoutAddress": true}"#);
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_unbound_gate_context_when_binding_is_required() -> Result<()> {
+    let unbound_attributes = [
+        valid_gate_context(),
+        gate_context_from_json(serde_json::json!([{
+            "id": "gid://shopify/GateConfiguration/1",
+            "hmac": "90283c3a225a0d06bed3f7de647ed8c6b70585effdfd85f3085690edeee7ec59",
+            "v": 2
+        }])),
+    ];
+
+    for attribute in unbound_attributes {
+        let mut input = input(attribute, discount_gate_configuration_id());
+        input.cart.buyer_identity = buyer_identity(Some("gid://shopify/Customer/1"), None);
+        input.discount_node.configuration =
+            configuration(r#"{"buyerBinding": "customer", "allowContextsWithoutAddress": true}"#);
+
+        assert_eq!(function(input)?, no_discount());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_binding_claimed_by_legacy_gate_context() -> Result<()> {
+    let attribute = gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "bd3862797c3e532f9f07e6672192d46792ee3591a0c7fe279e14d971eb541b37",
+        "binding": "customer"
+    }]));
+    let mut input = input(attribute, discount_gate_configuration_id());
+    input.cart.buyer_identity = buyer_identity(Some("gid://shopify/Customer/1"), None);
+    input.discount_node.configuration =
+        configuration(r#"{"buyerBinding": "customer", "allowContextsWithoutAddress": true}"#);
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_malformed_configuration() -> Result<()> {
+    let mut input = input(valid_gate_context(), discount_gate_configuration_id());
+    input.discount_node.configuration = configuration(r#"{"buyerBinding": "wallet"}"#);
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+fn wallet_bound_gate_context(address: &str) -> Option<input::InputCartAttribute> {
+    gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "73764ac9fd170d65c73ae52c26170107c2d17e24498189a8720cd31a5964d649",
+        "v": 2,
+        "address": address
+    }]))
+}
+
+#[test]
+fn test_discount_with_gate_context_bound_to_wallet() -> Result<()> {
+    let mut input = input(
+        wallet_bound_gate_context("0x71C7656EC7ab88b098defB751B7401B5f6d8976F"),
+        discount_gate_configuration_id(),
+    );
+    input.discount_node.configuration = configuration("{}");
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_gate_context_bound_to_another_wallet() -> Result<()> {
+    let input = input(
+        wallet_bound_gate_context("0x0000000000000000000000000000000000000001"),
+        discount_gate_configuration_id(),
+    );
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_malformed_wallet_address_in_gate_context() -> Result<()> {
+    let input = input(
+        wallet_bound_gate_context("71c7656ec7ab88b098defb751b7401b5f6d8976f"),
+        discount_gate_configuration_id(),
+    );
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_gate_context_without_wallet_and_no_compatibility_switch() -> Result<()> {
+    let mut input = input(valid_gate_context(), discount_gate_configuration_id());
+    input.discount_node.configuration = None;
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+fn expiring_gate_context() -> Option<input::InputCartAttribute> {
+    gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "19a02e3d00ba71e0cbd8035efa14f766ad439ae2c7ad69733db5fe74874bce47",
+        "v": 2,
+        "issuedAt": 1700000000,
+        "expiresAt": 1700003600
+    }]))
+}
+
+fn reference_time(value: &str) -> Option<input::InputDiscountNodeReferenceTime> {
+    Some(input::InputDiscountNodeReferenceTime {
+        value: value.to_string(),
+    })
+}
+
+#[test]
+fn test_discount_with_unexpired_gate_context() -> Result<()> {
+    let mut input = input(expiring_gate_context(), discount_gate_configuration_id());
+    input.discount_node.reference_time = reference_time("1700001800");
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_expired_gate_context() -> Result<()> {
+    let mut input = input(expiring_gate_context(), discount_gate_configuration_id());
+    input.discount_node.reference_time = reference_time("1700003901");
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_expired_gate_context_within_clock_skew() -> Result<()> {
+    let mut input = input(expiring_gate_context(), discount_gate_configuration_id());
+    input.discount_node.reference_time = reference_time("1700003900");
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_gate_context_issued_after_reference_time() -> Result<()> {
+    let mut within_clock_skew = input(expiring_gate_context(), discount_gate_configuration_id());
+    within_clock_skew.discount_node.reference_time = reference_time("1699999700");
+    assert_eq!(function(within_clock_skew)?, snowdevil_discount());
+
+    let mut beyond_clock_skew = input(expiring_gate_context(), discount_gate_configuration_id());
+    beyond_clock_skew.discount_node.reference_time = reference_time("1699999699");
+    assert_eq!(function(beyond_clock_skew)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_configured_clock_skew() -> Result<()> {
+    let mut input = input(expiring_gate_context(), discount_gate_configuration_id());
+    input.discount_node.reference_time = reference_time("1700003660");
+    input.discount_node.configuration =
+        configuration(r#"{"allowContextsWithoutAddress": true, "clockSkewSeconds": 30}"#);
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_expiring_gate_context_and_no_reference_time() -> Result<()> {
+    let input = input(expiring_gate_context(), discount_gate_configuration_id());
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_tampered_expiry_in_gate_context() -> Result<()> {
+    let attribute = gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "19a02e3d00ba71e0cbd8035efa14f766ad439ae2c7ad69733db5fe74874bce47",
+        "v": 2,
+        "issuedAt": 1700000000,
+        "expiresAt": 1800000000
+    }]));
+    let mut input = input(attribute, discount_gate_configuration_id());
+    input.discount_node.reference_time = reference_time("1700001800");
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[cfg(feature = "ed25519")]
+fn ed25519_key_pair(seed: u8) -> ed25519_compact::KeyPair {
+    ed25519_compact::KeyPair::from_seed(ed25519_compact::Seed::new([seed; 32]))
+}
+
+#[cfg(feature = "ed25519")]
+fn public_key(key_pair: &ed25519_compact::KeyPair) -> Option<input::InputDiscountNodePublicKey> {
+    Some(input::InputDiscountNodePublicKey {
+        value: hex::encode(key_pair.pk.as_ref()),
+    })
+}
+
+#[cfg(feature = "ed25519")]
+fn ed25519_gate_context(key_pair: &ed25519_compact::KeyPair) -> Option<input::InputCartAttribute> {
+    let signature = key_pair.sk.sign("gid://shopify/GateConfiguration/1", None);
+
+    gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "ed25519": hex::encode(signature.as_ref())
+    }]))
+}
+
+#[cfg(feature = "ed25519")]
+#[test]
+fn test_discount_with_hmac_and_ed25519_gate_contexts() -> Result<()> {
+    let key_pair = ed25519_key_pair(7);
+
+    for attribute in [valid_gate_context(), ed25519_gate_context(&key_pair)] {
+        let mut input = input(attribute, discount_gate_configuration_id());
+        input.discount_node.public_key = public_key(&key_pair);
+
+        assert_eq!(function(input)?, snowdevil_discount());
+    }
+    Ok(())
+}
+
+#[cfg(feature = "ed25519")]
+#[test]
+fn test_discount_with_ed25519_gate_context_and_only_a_public_key() -> Result<()> {
+    let key_pair = ed25519_key_pair(7);
+    let mut input = input(
+        ed25519_gate_context(&key_pair),
+        discount_gate_configuration_id(),
+    );
+    input.discount_node.secret_key = None;
+    input.discount_node.public_key = public_key(&key_pair);
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[cfg(feature = "ed25519")]
+#[test]
+fn test_discount_with_hmac_gate_context_and_only_a_public_key() -> Result<()> {
+    let mut input = input(valid_gate_context(), discount_gate_configuration_id());
+    input.discount_node.secret_key = None;
+    input.discount_node.public_key = public_key(&ed25519_key_pair(7));
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[cfg(feature = "ed25519")]
+#[test]
+fn test_discount_with_ed25519_gate_context_signed_by_another_key() -> Result<()> {
+    let mut input = input(
+        ed25519_gate_context(&ed25519_key_pair(8)),
+        discount_gate_configuration_id(),
+    );
+    input.discount_node.public_key = public_key(&ed25519_key_pair(7));
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[cfg(feature = "ed25519")]
+#[test]
+fn test_discount_with_ed25519_gate_context_and_no_public_key() -> Result<()> {
+    let input = input(
+        ed25519_gate_context(&ed25519_key_pair(7)),
+        discount_gate_configuration_id(),
+    );
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+// The public key from the seed [7; 32] and its signature of the version 1 message of
+// gate configuration 1
+const ED25519_PUBLIC_KEY: &str = "ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c";
+const ED25519_SIGNATURE: &str = "19f036a269abc1992c6ba15ae7c35625407f7e4118726792b120bbfe879b613e1bb726ee7ae19c9dc5dc9cc2ae71e01ac570df1c8b4ca642a54de4c8f3c8e506";
+
+#[test]
+fn test_discount_with_ed25519_gate_context_needs_the_feature() -> Result<()> {
+    let attribute = gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "ed25519": ED25519_SIGNATURE
+    }]));
+    let mut input = input(attribute, discount_gate_configuration_id());
+    input.discount_node.secret_key = None;
+    input.discount_node.public_key = Some(input::InputDiscountNodePublicKey {
+        value: ED25519_PUBLIC_KEY.to_string(),
+    });
+
+    let expected = if cfg!(feature = "ed25519") {
+        snowdevil_discount()
+    } else {
+        no_discount()
+    };
+    assert_eq!(function(input)?, expected);
+    Ok(())
+}
+
+#[cfg(feature = "ed25519")]
+#[test]
+fn test_discount_with_both_signature_schemes_in_gate_context() -> Result<()> {
+    let key_pair = ed25519_key_pair(7);
+    let signature = key_pair.sk.sign("gid://shopify/GateConfiguration/1", None);
+    let attribute = gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "bd3862797c3e532f9f07e6672192d46792ee3591a0c7fe279e14d971eb541b37",
+        "ed25519": hex::encode(signature.as_ref())
+    }]));
+    let mut input = input(attribute, discount_gate_configuration_id());
+    input.discount_node.public_key = public_key(&key_pair);
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+const ALLOWLISTED_WALLET: &str = "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf";
+#[cfg(feature = "personal-sign")]
+const UNLISTED_WALLET: &str = "0x2b5ad5c4795c026514f8317c7a215e218dccd6cf";
+
+// Private keys 1 and 2 control the allowlisted and unlisted wallets
+#[cfg(feature = "personal-sign")]
+fn wallet_signing_key(private_key: u8) -> k256::ecdsa::SigningKey {
+    let mut bytes = [0; 32];
+    bytes[31] = private_key;
+    k256::ecdsa::SigningKey::from_slice(&bytes).unwrap()
+}
+
+#[cfg(feature = "personal-sign")]
+fn personal_sign(signing_key: &k256::ecdsa::SigningKey, message: &str) -> String {
+    let prefixed_message = format!("\x19Ethereum Signed Message:\n{}{}", message.len(), message);
+    let digest = Keccak256::digest(prefixed_message.as_bytes());
+    let (signature, recovery_id) = signing_key.sign_prehash_recoverable(&digest).unwrap();
+
+    let mut bytes = signature.to_bytes().to_vec();
+    bytes.push(27 + recovery_id.to_byte());
+    format!("0x{}", hex::encode(bytes))
+}
+
+#[cfg(feature = "personal-sign")]
+fn personal_sign_gate_context(
+    signing_key: &k256::ecdsa::SigningKey,
+    address: &str,
+) -> Option<input::InputCartAttribute> {
+    let message = format!(
+        "v2\nid=gid://shopify/GateConfiguration/1\naddress={}",
+        address
+    );
+
+    gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "personalSign": personal_sign(signing_key, &message),
+        "v": 2,
+        "address": address
+    }]))
+}
+
+#[cfg(feature = "personal-sign")]
+#[test]
+fn test_discount_with_personal_sign_gate_context_from_allowlisted_wallet() -> Result<()> {
+    let mut input = input(
+        personal_sign_gate_context(&wallet_signing_key(1), ALLOWLISTED_WALLET),
+        discount_gate_configuration_id(),
+    );
+    input.discount_node.secret_key = None;
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+// The signature of the version 2 message of `personal_sign_gate_context` by the
+// allowlisted wallet. Signatures are deterministic, so it is the same for every build.
+const ALLOWLISTED_WALLET_PERSONAL_SIGN: &str = "0x3d246b58b398f29bb467cbfb953b6956727e549efffc29582d6f41307e4f2b221adfc1602617d5eae5e29203ffa6830e956726a9d4ef985fad4bd55c71def58d1c";
+
+#[test]
+fn test_discount_with_personal_sign_gate_context_needs_the_feature() -> Result<()> {
+    let attribute = gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "personalSign": ALLOWLISTED_WALLET_PERSONAL_SIGN,
+        "v": 2,
+        "address": ALLOWLISTED_WALLET
+    }]));
+    let mut input = input(attribute, discount_gate_configuration_id());
+    input.discount_node.secret_key = None;
+
+    let expected = if cfg!(feature = "personal-sign") {
+        snowdevil_discount()
+    } else {
+        no_discount()
+    };
+    assert_eq!(function(input)?, expected);
+    Ok(())
+}
+
+#[cfg(feature = "personal-sign")]
+#[test]
+fn test_discount_with_personal_sign_gate_context_from_unlisted_wallet() -> Result<()> {
+    let input = input(
+        personal_sign_gate_context(&wallet_signing_key(2), UNLISTED_WALLET),
+        discount_gate_configuration_id(),
+    );
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[cfg(feature = "personal-sign")]
+#[test]
+fn test_discount_with_personal_sign_gate_context_claiming_allowlisted_wallet() -> Result<()> {
+    let input = input(
+        personal_sign_gate_context(&wallet_signing_key(2), ALLOWLISTED_WALLET),
+        discount_gate_configuration_id(),
+    );
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[cfg(feature = "personal-sign")]
+#[test]
+fn test_discount_with_personal_sign_gate_context_for_gate_without_allowlist() -> Result<()> {
+    let message = format!(
+        "v2\nid=gid://shopify/GateConfiguration/2\naddress={}",
+        ALLOWLISTED_WALLET
+    );
+    let attribute = gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/2",
+        "personalSign": personal_sign(&wallet_signing_key(1), &message),
+        "v": 2,
+        "address": ALLOWLISTED_WALLET
+    }]));
+    let input = input(
+        attribute,
+        Some(input::InputDiscountNodeMetafield {
+            value: "gid://shopify/GateConfiguration/2".to_string(),
+        }),
+    );
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_malformed_personal_sign_in_gate_context() -> Result<()> {
+    let attribute = gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "personalSign": "0x1234",
+        "v": 2,
+        "address": ALLOWLISTED_WALLET
+    }]));
+    let input = input(attribute, discount_gate_configuration_id());
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+const MERKLE_CUSTOMERS_CSV: &str = "customer_id,tokens
+gid://shopify/Customer/1,3
+gid://shopify/Customer/2,1
+gid://shopify/Customer/3,12
+";
+
+#[cfg(feature = "personal-sign")]
+const MERKLE_WALLETS_CSV: &str = "address
+0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf
+0x71c7656ec7ab88b098defb751b7401b5f6d8976f
+0x0000000000000000000000000000000000000001
+0x0000000000000000000000000000000000000002
+0x0000000000000000000000000000000000000003
+";
+
+fn with_requirements(mut input: input::ResponseData, value: &str) -> input::ResponseData {
+    if let input::InputCartLinesMerchandise::ProductVariant(variant) =
+        &mut input.cart.lines[0].merchandise
+    {
+        variant.product.gates[0].configuration.requirements = Some(
+            input::InputCartLinesMerchandiseOnProductVariantProductGatesConfigurationRequirements {
+                value: value.to_string(),
+            },
+        );
+    }
+
+    input
+}
+
+fn merkle_requirements(tree: &merkle::MerkleTree, leaves: &str) -> String {
+    serde_json::json!({
+        "merkleRoot": hex::encode(tree.root()),
+        "merkleLeaves": leaves
+    })
+    .to_string()
+}
+
+fn merkle_gate_context(
+    leaf: &str,
+    proof: &[merkle::Hash],
+    extra: serde_json::Value,
+) -> Option<input::InputCartAttribute> {
+    let mut item = serde_json::json!({
+        "id": "gid://shopify/GateConfiguration/1",
+        "leaf": leaf,
+        "proof": proof.iter().map(hex::encode).collect::<Vec<_>>()
+    });
+    item.as_object_mut()
+        .unwrap()
+        .extend(extra.as_object().unwrap().clone());
+
+    gate_context_from_json(serde_json::json!([item]))
+}
+
+fn customer_merkle_input(leaf: &str, buyer: &str) -> input::ResponseData {
+    let tree = merkle::MerkleTree::from_csv(MERKLE_CUSTOMERS_CSV, MerkleLeaves::Customer);
+    let proof = tree.proof(leaf).unwrap_or_default();
+    let mut input = with_requirements(
+        input(
+            merkle_gate_context(leaf, &proof, serde_json::json!({})),
+            discount_gate_configuration_id(),
+        ),
+        &merkle_requirements(&tree, "customer"),
+    );
+    input.discount_node.secret_key = None;
+    input.discount_node.configuration = configuration("{}");
+    input.cart.buyer_identity = buyer_identity(Some(buyer), None);
+
+    input
+}
+
+#[test]
+fn test_discount_with_merkle_proof_for_buyer() -> Result<()> {
+    let input = customer_merkle_input("gid://shopify/Customer/3", "gid://shopify/Customer/3");
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_merkle_proof_for_another_customer() -> Result<()> {
+    let input = customer_merkle_input("gid://shopify/Customer/3", "gid://shopify/Customer/4");
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_merkle_proof_for_customer_outside_tree() -> Result<()> {
+    let input = customer_merkle_input("gid://shopify/Customer/4", "gid://shopify/Customer/4");
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_merkle_proof_and_root_of_another_tree() -> Result<()> {
+    let other_tree = merkle::MerkleTree::from_csv(
+        "gid://shopify/Customer/3\ngid://shopify/Customer/5",
+        MerkleLeaves::Customer,
+    );
+    let input = with_requirements(
+        customer_merkle_input("gid://shopify/Customer/3", "gid://shopify/Customer/3"),
+        &merkle_requirements(&other_tree, "customer"),
+    );
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[cfg(feature = "personal-sign")]
+fn wallet_merkle_input(signed: bool) -> input::ResponseData {
+    let tree = merkle::MerkleTree::from_csv(MERKLE_WALLETS_CSV, MerkleLeaves::Address);
+    let proof = tree.proof(ALLOWLISTED_WALLET).unwrap();
+    let message = format!(
+        "v2\nid=gid://shopify/GateConfiguration/1\naddress={}",
+        ALLOWLISTED_WALLET
+    );
+    let mut extra = serde_json::json!({ "v": 2, "address": ALLOWLISTED_WALLET });
+    if signed {
+        extra["personalSign"] = personal_sign(&wallet_signing_key(1), &message).into();
+    }
+
+    let mut input = with_requirements(
+        input(
+            merkle_gate_context(ALLOWLISTED_WALLET, &proof, extra),
+            discount_gate_configuration_id(),
+        ),
+        &merkle_requirements(&tree, "address"),
+    );
+    input.discount_node.secret_key = None;
+
+    input
+}
+
+#[cfg(feature = "personal-sign")]
+#[test]
+fn test_discount_with_merkle_proof_for_signing_wallet() -> Result<()> {
+    assert_eq!(function(wallet_merkle_input(true))?, snowdevil_discount());
+    Ok(())
+}
+
+#[cfg(feature = "personal-sign")]
+#[test]
+fn test_discount_with_merkle_proof_for_wallet_without_signature() -> Result<()> {
+    assert_eq!(function(wallet_merkle_input(false))?, no_discount());
+    Ok(())
+}
+
+fn gate_context_envelope(envelope: serde_json::Value) -> Option<input::InputCartAttribute> {
+    Some(input::InputCartAttribute {
+        value: Some(envelope.to_string()),
+    })
+}
+
+#[test]
+fn test_discount_with_version_2_gate_context_envelope() -> Result<()> {
+    let attribute = gate_context_envelope(serde_json::json!({
+        "v": 2,
+        "items": [{
+            "id": "gid://shopify/GateConfiguration/1",
+            "hmac": "bd3862797c3e532f9f07e6672192d46792ee3591a0c7fe279e14d971eb541b37"
+        }]
+    }));
+    let input = input(attribute, discount_gate_configuration_id());
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_unknown_gate_context_envelope_version() -> Result<()> {
+    let attribute = gate_context_envelope(serde_json::json!({
+        "v": 3,
+        "items": [{
+            "id": "gid://shopify/GateConfiguration/1",
+            "hmac": "bd3862797c3e532f9f07e6672192d46792ee3591a0c7fe279e14d971eb541b37"
+        }]
+    }));
+    let input = input(attribute, discount_gate_configuration_id());
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_rejected_gate_contexts_have_a_reason() {
+    let errors = [
+        (
+            "{\"v\":3,\"items\":[]}",
+            GateError::UnknownVersion("3".to_string()),
+        ),
+        (
+            "{\"v\":\"2\",\"items\":[]}",
+            GateError::UnknownVersion("\"2\"".to_string()),
+        ),
+        (
+            "{\"items\":[]}",
+            GateError::MalformedContext("envelope without a version".to_string()),
+        ),
+        (
+            "\"gid://shopify/GateConfiguration/1\"",
+            GateError::MalformedContext("neither an array of items nor an envelope".to_string()),
+        ),
+    ];
+
+    for (value, error) in errors {
+        assert_eq!(parse_gate_context(value).err(), Some(error));
+    }
+    assert!(matches!(
+        parse_gate_context("{\"v\":2}"),
+        Err(GateError::MalformedContext(reason)) if reason.starts_with("invalid version 2 envelope")
+    ));
+    assert!(matches!(
+        parse_gate_context("[{\"id\": 1}]"),
+        Err(GateError::MalformedContext(reason)) if reason.starts_with("invalid items")
+    ));
+}
+
+#[cfg(feature = "compact")]
+fn compact_gate_context(items: serde_json::Value) -> Option<input::InputCartAttribute> {
+    let items: Vec<GateContextItem> = serde_json::from_value(items).unwrap();
+
+    Some(input::InputCartAttribute {
+        value: Some(compact::encode(&items)),
+    })
+}
+
+#[cfg(feature = "compact")]
+#[test]
+fn test_discount_with_compact_gate_context() -> Result<()> {
+    let attribute = compact_gate_context(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "73764ac9fd170d65c73ae52c26170107c2d17e24498189a8720cd31a5964d649",
+        "v": 2,
+        "address": "0x71c7656ec7ab88b098defb751b7401b5f6d8976f"
+    }]));
+    let mut input = input(attribute, discount_gate_configuration_id());
+    input.discount_node.configuration = configuration("{}");
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[cfg(feature = "compact")]
+#[test]
+fn test_discount_with_expiring_compact_gate_context() -> Result<()> {
+    let attribute = compact_gate_context(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "19a02e3d00ba71e0cbd8035efa14f766ad439ae2c7ad69733db5fe74874bce47",
+        "v": 2,
+        "issuedAt": 1700000000,
+        "expiresAt": 1700003600
+    }]));
+    let mut input = input(attribute, discount_gate_configuration_id());
+    input.discount_node.reference_time = reference_time("1700001000");
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[cfg(feature = "compact")]
+#[test]
+fn test_discount_with_tampered_compact_gate_context() -> Result<()> {
+    let attribute = compact_gate_context(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "73764ac9fd170d65c73ae52c26170107c2d17e24498189a8720cd31a5964d649",
+        "v": 2,
+        "address": "0x0000000000000000000000000000000000000001"
+    }]));
+    let mut input = input(attribute, discount_gate_configuration_id());
+    input.discount_node.configuration = configuration("{}");
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_compact_gate_context_needs_the_feature() -> Result<()> {
+    // The compact encoding of `gate_context_with_nonce`
+    let attribute = Some(input::InputCartAttribute {
+        value: Some("c1.ARBvHCqbDn1MNaix8OLTxLWmk0eBy40RebKZRcRvKi13lQ".to_string()),
+    });
+    let input = input(attribute, discount_gate_configuration_id());
+
+    let expected = if cfg!(feature = "compact") {
+        snowdevil_discount()
+    } else {
+        no_discount()
+    };
+    assert_eq!(function(input)?, expected);
+    Ok(())
+}
+
+#[cfg(feature = "compact")]
+#[test]
+fn test_rejected_compact_gate_contexts_have_a_reason() {
+    let reasons = [
+        ("c1.AQ", "truncated compact item"),
+        ("c1.ASA", "unknown compact item flags 0x20"),
+        ("c1.AQw", "compact item bound to two buyer identities"),
+        ("c1.__________wI", "truncated compact item"),
+    ];
+
+    for (value, reason) in reasons {
+        assert_eq!(
+            parse_gate_context(value).err(),
+            Some(GateError::MalformedContext(reason.to_string()))
+        );
+    }
+    assert!(matches!(
+        parse_gate_context("c1.not base64"),
+        Err(GateError::MalformedContext(reason)) if reason.starts_with("invalid base64url")
+    ));
+}
+
+// Run with `cargo test gate_context_size -- --nocapture` to print the sizes
+#[cfg(feature = "compact")]
+#[test]
+fn test_compact_gate_context_size() {
+    for gates in [1, 10, 50] {
+        let items: Vec<serde_json::Value> = (0..gates)
+            .map(|gate| {
+                serde_json::json!({
+                    "id": format!("gid://shopify/GateConfiguration/{}", 1_000_000_000 + gate),
+                    "hmac": "73764ac9fd170d65c73ae52c26170107c2d17e24498189a8720cd31a5964d649",
+                    "v": 2,
+                    "address": "0x71c7656ec7ab88b098defb751b7401b5f6d8976f",
+                    "issuedAt": 1700000000,
+                    "expiresAt": 1700003600
+                })
+            })
+            .collect();
+        let json = serde_json::json!({ "v": 2, "items": items }).to_string();
+        let compact = compact_gate_context(serde_json::Value::Array(items))
+            .and_then(|attribute| attribute.value)
+            .unwrap();
+
+        eprintln!(
+            "{} gates: {} bytes as JSON, {} bytes compact",
+            gates,
+            json.len(),
+            compact.len()
+        );
+        assert!(compact.len() * 3 < json.len());
+        assert_eq!(
+            parse_gate_context(&compact).map(|items| items.len()),
+            Ok(gates)
+        );
+    }
+}
+
+fn aggregate_gate_context(
+    id: Option<&str>,
+    ids: &[&str],
+    hmac: &str,
+) -> Option<input::InputCartAttribute> {
+    gate_context_from_json(serde_json::json!([{
+        "id": id,
+        "ids": ids,
+        "hmac": hmac,
+        "v": 2
+    }]))
+}
+
+#[test]
+fn test_discount_with_aggregate_gate_context() -> Result<()> {
+    let attribute = aggregate_gate_context(
+        None,
+        &[
+            "gid://shopify/GateConfiguration/2",
+            "gid://shopify/GateConfiguration/1",
+        ],
+        "a587e6e207c419e6dfca8968e57d93c607f49f7f7d20443ed641708dfdf0e60f",
+    );
+    let input = input(attribute, discount_gate_configuration_id());
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_on_two_products_with_aggregate_gate_context() -> Result<()> {
+    let attribute = aggregate_gate_context(
+        None,
+        &[
+            "gid://shopify/GateConfiguration/1",
+            "gid://shopify/GateConfiguration/2",
+        ],
+        "a587e6e207c419e6dfca8968e57d93c607f49f7f7d20443ed641708dfdf0e60f",
+    );
+    let input = input(
+        attribute,
+        Some(input::InputDiscountNodeMetafield {
+            value: "gid://shopify/GateConfiguration/2".to_string(),
+        }),
+    );
+
+    let result = function(input)?;
+    assert_eq!(result.discounts.len(), 1);
+    assert_eq!(result.discounts[0].targets.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_aggregate_gate_context_for_other_gates() -> Result<()> {
+    let attribute = aggregate_gate_context(
+        None,
+        &[
+            "gid://shopify/GateConfiguration/2",
+            "gid://shopify/GateConfiguration/3",
+        ],
+        "6a7e51743d6d7100d68a2a86482cdd393f63cf26ff9b5afe7d2daff72b0cdca6",
+    );
+    let input = input(attribute, discount_gate_configuration_id());
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_aggregate_signature_claiming_another_gate() -> Result<()> {
+    let attribute = aggregate_gate_context(
+        Some("gid://shopify/GateConfiguration/1"),
+        &[
+            "gid://shopify/GateConfiguration/2",
+            "gid://shopify/GateConfiguration/3",
+        ],
+        "6a7e51743d6d7100d68a2a86482cdd393f63cf26ff9b5afe7d2daff72b0cdca6",
+    );
+    let input = input(attribute, discount_gate_configuration_id());
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_aggregate_gate_context_for_allowlisted_gate() -> Result<()> {
+    // Gate 1 has an allowlist, which does not apply to items signed by the app
+    let attribute = aggregate_gate_context(
+        None,
+        &["gid://shopify/GateConfiguration/1"],
+        "4bec06e7bad2fec3ebbe1e78fe88e4e46bd71fbab85ad995d702171a746d1fd5",
+    );
+    let input = input(attribute, discount_gate_configuration_id());
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[cfg(feature = "personal-sign")]
+#[test]
+fn test_discount_with_aggregate_gate_context_signed_by_allowlisted_wallet() -> Result<()> {
+    let message = format!(
+        "v2\nids=gid://shopify/GateConfiguration/1\naddress={}",
+        ALLOWLISTED_WALLET
+    );
+    let attribute = gate_context_from_json(serde_json::json!([{
+        "ids": ["gid://shopify/GateConfiguration/1"],
+        "personalSign": personal_sign(&wallet_signing_key(1), &message),
+        "v": 2,
+        "address": ALLOWLISTED_WALLET
+    }]));
+    let input = input(attribute, discount_gate_configuration_id());
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+fn derived_keyring_secret_key() -> Option<input::InputDiscountNodeSecretKey> {
+    Some(input::InputDiscountNodeSecretKey {
+        value: r#"{ "keys": [{ "secret": "secret-key", "derivation": "hkdf" }] }"#.to_string(),
+    })
+}
+
+fn gate_context_with_hmac(hmac: &str) -> Option<input::InputCartAttribute> {
+    gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": hmac
+    }]))
+}
+
+#[test]
+fn test_gate_key_test_vectors() {
+    let test_vectors: serde_json::Value =
+        serde_json::from_str(include_str!("../test-vectors/gate-keys.json")).unwrap();
+    assert_eq!(
+        test_vectors["salt"].as_str().map(str::as_bytes),
+        Some(GATE_KEY_SALT)
+    );
+
+    for test_vector in test_vectors["vectors"].as_array().unwrap() {
+        let field = |name: &str| test_vector[name].as_str().unwrap();
+        let gate_key = gate_key(field("secret"), field("gateConfigurationId"));
+        let hmac = hmac_signature(&gate_key, field("message")).finalize();
+
+        assert_eq!(hex::encode(gate_key), field("gateKey"));
+        assert_eq!(hex::encode(hmac.into_bytes()), field("hmac"));
+    }
+}
+
+#[test]
+fn test_discount_with_gate_context_signed_by_derived_gate_key() -> Result<()> {
+    let mut input = input(
+        gate_context_with_hmac("e0140d80cbe36981da856ca0acedb1f42e54a136fe1a462e8fff356512bd2ae9"),
+        discount_gate_configuration_id(),
+    );
+    input.discount_node.secret_key = derived_keyring_secret_key();
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_gate_context_signed_by_master_secret_in_derivation_mode() -> Result<()> {
+    let mut input = input(valid_gate_context(), discount_gate_configuration_id());
+    input.discount_node.secret_key = derived_keyring_secret_key();
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_gate_context_signed_by_another_gates_key() -> Result<()> {
+    let mut input = input(
+        gate_context_with_hmac("ac99bba2dac662ab3c01ed88c9241d1039bba88870034502e09102add515115a"),
+        discount_gate_configuration_id(),
+    );
+    input.discount_node.secret_key = derived_keyring_secret_key();
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+const NONCE: &str = "6f1c2a9b0e7d4c35a8b1f0e2d3c4b5a6";
+
+fn gate_context_with_nonce() -> Option<input::InputCartAttribute> {
+    gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "934781cb8d1179b29945c46f2a2d7795acc1211b20a9677cbc5ecc8f08bdb36e",
+        "v": 2,
+        "nonce": NONCE
+    }]))
+}
+
+fn revoked_nonces(value: &str) -> Option<input::InputDiscountNodeRevokedNonces> {
+    Some(input::InputDiscountNodeRevokedNonces {
+        value: value.to_string(),
+    })
+}
+
+#[test]
+fn test_discount_with_gate_context_not_in_revocation_list() -> Result<()> {
+    let mut input = input(gate_context_with_nonce(), discount_gate_configuration_id());
+    input.discount_node.revoked_nonces = revoked_nonces(r#"["0a1b", "ff00"]"#);
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_revoked_gate_context() -> Result<()> {
+    let mut input = input(gate_context_with_nonce(), discount_gate_configuration_id());
+    input.discount_node.revoked_nonces =
+        revoked_nonces(&serde_json::json!(["0a1b", NONCE, "ff00"]).to_string());
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_unsorted_revocation_list() -> Result<()> {
+    let mut input = input(gate_context_with_nonce(), discount_gate_configuration_id());
+    input.discount_node.revoked_nonces =
+        revoked_nonces(&serde_json::json!(["ff00", "0a1b"]).to_string());
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[cfg(feature = "compact")]
+#[test]
+fn test_discount_with_revoked_compact_gate_context() -> Result<()> {
+    let attribute = compact_gate_context(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "934781cb8d1179b29945c46f2a2d7795acc1211b20a9677cbc5ecc8f08bdb36e",
+        "v": 2,
+        "nonce": NONCE
+    }]));
+
+    let unrevoked_input = input(attribute.clone(), discount_gate_configuration_id());
+    assert_eq!(function(unrevoked_input)?, snowdevil_discount());
+
+    let mut revoked_input = input(attribute, discount_gate_configuration_id());
+    revoked_input.discount_node.revoked_nonces =
+        revoked_nonces(&serde_json::json!([NONCE]).to_string());
+    assert_eq!(function(revoked_input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_malformed_revocation_list() -> Result<()> {
+    let mut input = input(gate_context_with_nonce(), discount_gate_configuration_id());
+    input.discount_node.revoked_nonces = revoked_nonces(NONCE);
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_nonce_smuggling_a_signed_field() -> Result<()> {
+    let attribute = gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "9710dfbaf4a9e64fd22f9ef26ac1f284e1675499f136df7cd0815bfe54457978",
+        "v": 2,
+        "nonce": "a\ncustomer=gid://shopify/Customer/1"
+    }]));
+    let input = input(attribute, discount_gate_configuration_id());
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+fn with_reaction(input: input::ResponseData, value: &str) -> input::ResponseData {
+    with_line_reaction(input, 0, value)
+}
+
+fn with_line_reaction(
+    mut input: input::ResponseData,
+    line: usize,
+    value: &str,
+) -> input::ResponseData {
+    if let input::InputCartLinesMerchandise::ProductVariant(variant) =
+        &mut input.cart.lines[line].merchandise
+    {
+        variant.product.gates[0].configuration.metafield = Some(
+            input::InputCartLinesMerchandiseOnProductVariantProductGatesConfigurationMetafield {
+                value: value.to_string(),
+            },
+        );
+    }
+
+    input
+}
+
+#[test]
+fn test_discount_with_invalid_reaction() -> Result<()> {
+    let reactions = [
+        "not json",
+        "{\"name\":\"Snowdevil discount\",\"type\":\"discount\",\"discount\":{\"type\":\"percentage\",\"value\":\"lots\"}}",
+        "{\"name\":\"Snowdevil discount\",\"type\":\"discount\",\"discount\":{\"type\":\"free\"}}",
+    ];
+
+    for reaction in reactions {
+        let input = with_reaction(
+            input(valid_gate_context(), discount_gate_configuration_id()),
+            reaction,
+        );
+        assert_eq!(function(input)?, no_discount());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_malformed_public_key_falls_back_to_hmac() -> Result<()> {
+    let mut input = input(valid_gate_context(), discount_gate_configuration_id());
+    input.discount_node.public_key = Some(input::InputDiscountNodePublicKey {
+        value: "not hex".to_string(),
+    });
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[test]
+fn test_metafield_errors() {
+    assert_eq!(
+        parse_keyring_from_metafield(None).err(),
+        Some(GateError::MissingMetafield("secret_key"))
+    );
+    #[cfg(feature = "ed25519")]
+    assert_eq!(
+        parse_public_key_from_metafield(Some(&input::InputDiscountNodePublicKey {
+            value: "not hex".to_string(),
+        }))
+        .err(),
+        Some(GateError::BadHex("public_key"))
+    );
+    assert_eq!(
+        parse_gate_reaction_from_metafield(None).err(),
+        Some(GateError::MissingMetafield("reaction"))
+    );
+    assert!(matches!(
+        parse_configuration_from_metafield(configuration("{\"buyerBinding\": 1}").as_ref()),
+        Err(GateError::MalformedMetafield("configuration", _))
+    ));
+}
+
+fn traced_gate(gate_subject: u32, gate_configuration: u32) -> trace::GateTrace {
+    trace::GateTrace {
+        gate_subject_id: format!("gid://shopify/GateSubject/{}", gate_subject),
+        gate_configuration_id: format!("gid://shopify/GateConfiguration/{}", gate_configuration),
+        ..trace::GateTrace::default()
+    }
+}
+
+fn traced_line(variant: u32, gates: Vec<trace::GateTrace>) -> trace::LineTrace {
+    trace::LineTrace {
+        variant_id: format!("gid://shopify/ProductVariant/{}", variant),
+        gates,
+    }
+}
+
+#[test]
+fn test_decision_trace_of_each_gate_subject() {
+    let mut input = input(valid_gate_context(), discount_gate_configuration_id());
+    input.discount_node.configuration =
+        configuration("{\"allowContextsWithoutAddress\": true, \"trace\": true}");
+
+    let (result, trace) = evaluate_gates(input);
+    let expected = trace::DecisionTrace {
+        lines: vec![
+            traced_line(
+                1,
+                vec![trace::GateTrace {
+                    covered_by_discount: true,
+                    context_item_found: true,
+                    signature_verified: true,
+                    reaction: Some("Snowdevil discount".to_string()),
+                    ..traced_gate(1, 1)
+                }],
+            ),
+            traced_line(2, vec![traced_gate(2, 2)]),
+            traced_line(3, vec![traced_gate(3, 2)]),
+            traced_line(4, vec![traced_gate(4, 3)]),
+        ],
+        error: None,
+    };
+
+    assert_eq!(result, snowdevil_discount());
+    assert_eq!(trace, Some(expected));
+}
+
+#[test]
+fn test_decision_trace_of_rejected_gate_context_item() {
+    let attribute = gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "0000000000000000000000000000000000000000000000000000000000000000"
+    }]));
+    let mut input = with_reaction(
+        input(attribute, discount_gate_configuration_id()),
+        "not json",
+    );
+    input.discount_node.configuration =
+        configuration("{\"allowContextsWithoutAddress\": true, \"trace\": true}");
+
+    let (_, trace) = evaluate_gates(input);
+    let gate = &trace.unwrap().lines[0].gates[0];
+
+    assert!(gate.covered_by_discount && gate.context_item_found);
+    assert!(!gate.signature_verified);
+    assert_eq!(gate.reaction, None);
+    assert_eq!(gate.error, None);
+}
+
+#[test]
+fn test_decision_trace_of_gate_with_invalid_reaction() {
+    let mut input = with_reaction(
+        input(valid_gate_context(), discount_gate_configuration_id()),
+        "not json",
+    );
+    input.discount_node.configuration =
+        configuration("{\"allowContextsWithoutAddress\": true, \"trace\": true}");
+
+    let (result, trace) = evaluate_gates(input);
+    let gate = &trace.unwrap().lines[0].gates[0];
+
+    assert_eq!(result, no_discount());
+    assert!(gate.signature_verified);
+    assert_eq!(gate.reaction, None);
+    assert!(gate
+        .error
+        .as_deref()
+        .unwrap()
+        .starts_with("invalid reaction: "));
+}
+
+#[test]
+fn test_decision_trace_of_cart_without_gate_context() {
+    let mut input = input(None, discount_gate_configuration_id());
+    input.discount_node.configuration = configuration("{\"trace\": true}");
+
+    let (result, trace) = evaluate_gates(input);
+    let expected = trace::GateTrace {
+        covered_by_discount: true,
+        ..traced_gate(1, 1)
+    };
+
+    assert_eq!(result, no_discount());
+    assert_eq!(trace.unwrap().lines[0].gates, vec![expected]);
+}
+
+#[test]
+fn test_decision_trace_of_cart_with_malformed_gate_context() {
+    let mut input = input(
+        Some(input::InputCartAttribute {
+            value: Some("{\"items\": []}".to_string()),
+        }),
+        discount_gate_configuration_id(),
+    );
+    input.discount_node.configuration = configuration("{\"trace\": true}");
+
+    let (result, trace) = evaluate_gates(input);
+    let expected = trace::DecisionTrace {
+        lines: vec![],
+        error: Some("malformed gate context: envelope without a version".to_string()),
+    };
+
+    assert_eq!(result, no_discount());
+    assert_eq!(trace, Some(expected));
+}
+
+#[test]
+fn test_decision_trace_of_discount_without_gate_configuration_id() {
+    let mut input = input(valid_gate_context(), None);
+    input.discount_node.configuration = configuration("{\"trace\": true}");
+
+    let (result, trace) = evaluate_gates(input);
+
+    assert_eq!(result, no_discount());
+    assert_eq!(
+        trace.unwrap().error.as_deref(),
+        Some("missing gate_configuration_id metafield")
+    );
+}
+
+#[test]
+fn test_decision_trace_of_malformed_configuration() {
+    let mut input = input(valid_gate_context(), discount_gate_configuration_id());
+    input.discount_node.configuration = configuration("{\"trace\": yes}");
+
+    let (result, trace) = evaluate_gates(input);
+
+    assert_eq!(result, no_discount());
+    assert_eq!(
+        trace.map(|trace| trace.error.is_some()),
+        cfg!(feature = "trace").then_some(true)
+    );
+}
+
+#[test]
+fn test_decision_trace_is_opt_in() {
+    let input = input(valid_gate_context(), discount_gate_configuration_id());
+
+    let (_, trace) = evaluate_gates(input);
+
+    assert_eq!(trace.is_some(), cfg!(feature = "trace"));
+}
+
+#[test]
+fn test_decision_trace_format() {
+    let trace = trace::DecisionTrace {
+        lines: vec![traced_line(
+            1,
+            vec![trace::GateTrace {
+                covered_by_discount: true,
+                context_item_found: true,
+                error: Some("invalid reaction: discount value lots is not a number".to_string()),
+                ..traced_gate(1, 1)
+            }],
+        )],
+        error: None,
+    };
+
+    assert_eq!(
+        trace.to_log_records(),
+        vec![
+            r#"{"decisionTrace":{"lines":1,"error":null}}"#,
+            concat!(
+                r#"{"decisionTrace":{"line":0,"variantId":"gid://shopify/ProductVariant/1","gates":[{"#,
+                r#""gateSubjectId":"gid://shopify/GateSubject/1","#,
+                r#""gateConfigurationId":"gid://shopify/GateConfiguration/1","#,
+                r#""coveredByDiscount":true,"contextItemFound":true,"signatureVerified":false,"#,
+                r#""reaction":null,"#,
+                r#""error":"invalid reaction: discount value lots is not a number"}]}}"#
+            )
+        ]
+    );
+}
+
+#[test]
+fn test_decision_trace_records_fit_in_the_function_logs() {
+    let mut input =
+        overlapping_gates_input(r#"{"allowContextsWithoutAddress": true, "trace": true}"#);
+    let long_name = "Snowdevil holders ".repeat(20);
+    for line in &mut input.cart.lines {
+        if let input::InputCartLinesMerchandise::ProductVariant(variant) = &mut line.merchandise {
+            for gate_subject in 10..22 {
+                variant.product.gates.push(
+                    serde_json::from_value(serde_json::json!({
+                        "id": format!("gid://shopify/GateSubject/{}", gate_subject),
+                        "configuration": {
+                            "id": "gid://shopify/GateConfiguration/1",
+                            "handle": "tokengating-example-app",
+                            "metafield": {
+                                "value": serde_json::json!({
+                                    "name": long_name,
+                                    "discount": { "type": "percentage", "value": 10 }
+                                })
+                                .to_string()
+                            }
+                        }
+                    }))
+                    .unwrap(),
+                );
+            }
+        }
+    }
+
+    let (_, trace) = evaluate_gates(input);
+    let trace = trace.unwrap();
+    let records = trace.to_log_records();
+
+    let mut traced_gates = 0;
+    for record in &records {
+        assert!(record.len() <= trace::MAX_RECORD_LENGTH, "{}", record);
+        let record: serde_json::Value = serde_json::from_str(record).unwrap();
+        traced_gates += record["decisionTrace"]["gates"]
+            .as_array()
+            .map_or(0, Vec::len);
+    }
+    assert_eq!(trace.lines.len(), 4);
+    assert!(records.len() > 1 + trace.lines.len());
+    assert_eq!(
+        traced_gates,
+        trace
+            .lines
+            .iter()
+            .map(|line| line.gates.len())
+            .sum::<usize>()
+    );
+}
+
+fn tokenfolk_gate_context() -> Option<input::InputCartAttribute> {
+    gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/2",
+        "hmac": "f095024f57f4642cad6c442b954dd3bd24e4cdc2c180209cda3f5cf2a0c28703"
+    }]))
+}
+
+fn tokenfolk_gate_configuration_id() -> Option<input::InputDiscountNodeMetafield> {
+    Some(input::InputDiscountNodeMetafield {
+        value: "gid://shopify/GateConfiguration/2".to_string(),
+    })
+}
+
+#[test]
+fn test_discount_per_reaction() -> Result<()> {
+    let input = with_line_reaction(
+        input(tokenfolk_gate_context(), tokenfolk_gate_configuration_id()),
+        2,
+        "{\"name\":\"Tokenfolk percentage\",\"type\":\"discount\",\"discount\":{\"type\":\"percentage\",\"value\":15}}",
+    );
+
+    let result = function(input)?;
+    let expected = crate::output::FunctionResult {
+        discounts: vec![
+            crate::output::Discount {
+                message: Some("Tokenfolk discount".to_string()),
+                targets: vec![crate::output::Target::ProductVariant(
+                    crate::output::ProductVariantTarget {
+                        id: "gid://shopify/ProductVariant/2".to_string(),
+                        quantity: None,
+                    },
+                )],
+                value: crate::output::Value::FixedAmount(crate::output::FixedAmount {
+                    amount: "10".to_string(),
+                    applies_to_each_item: None,
+                }),
+            },
+            crate::output::Discount {
+                message: Some("Tokenfolk percentage".to_string()),
+                targets: vec![crate::output::Target::ProductVariant(
+                    crate::output::ProductVariantTarget {
+                        id: "gid://shopify/ProductVariant/3".to_string(),
+                        quantity: None,
+                    },
+                )],
+                value: crate::output::Value::Percentage(crate::output::Percentage {
+                    value: "15".to_string(),
+                }),
+            },
+        ],
+        discount_application_strategy: crate::output::DiscountApplicationStrategy::MAXIMUM,
+    };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_discount_per_reaction_with_the_same_name() -> Result<()> {
+    let input = with_line_reaction(
+        input(tokenfolk_gate_context(), tokenfolk_gate_configuration_id()),
+        2,
+        "{\"name\":\"Tokenfolk discount\",\"type\":\"discount\",\"discount\":{\"type\":\"amount\",\"value\":\"12.5\"}}",
+    );
+
+    let result = function(input)?;
+    let amounts: Vec<_> = result
+        .discounts
+        .iter()
+        .map(|discount| (discount.targets.len(), discount.value.clone()))
+        .collect();
+
+    assert_eq!(
+        amounts,
+        vec![
+            (
+                1,
+                crate::output::Value::FixedAmount(crate::output::FixedAmount {
+                    amount: "10".to_string(),
+                    applies_to_each_item: None,
+                })
+            ),
+            (
+                1,
+                crate::output::Value::FixedAmount(crate::output::FixedAmount {
+                    amount: "12.5".to_string(),
+                    applies_to_each_item: None,
+                })
+            ),
+        ]
+    );
+    Ok(())
+}
+
+fn gate_configuration_ids(value: &str) -> Option<input::InputDiscountNodeMetafield> {
+    Some(input::InputDiscountNodeMetafield {
+        value: value.to_string(),
+    })
+}
+
+#[test]
+fn test_discount_covering_several_gate_configurations() -> Result<()> {
+    let attribute = gate_context_from_json(serde_json::json!([
+        {
+            "id": "gid://shopify/GateConfiguration/1",
+            "hmac": "bd3862797c3e532f9f07e6672192d46792ee3591a0c7fe279e14d971eb541b37"
+        },
+        {
+            "id": "gid://shopify/GateConfiguration/2",
+            "hmac": "f095024f57f4642cad6c442b954dd3bd24e4cdc2c180209cda3f5cf2a0c28703"
+        }
+    ]));
+    let input = input(
+        attribute,
+        gate_configuration_ids(
+            r#" ["gid://shopify/GateConfiguration/1", "gid://shopify/GateConfiguration/2"] "#,
+        ),
+    );
+
+    let result = function(input)?;
+    let targets: Vec<_> = result
+        .discounts
+        .iter()
+        .map(|discount| (discount.message.as_deref(), discount.targets.len()))
+        .collect();
+
+    assert_eq!(
+        targets,
+        vec![
+            (Some("Snowdevil discount"), 1),
+            (Some("Tokenfolk discount"), 2)
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_discount_covering_several_gate_configurations_only_unlocks_those() -> Result<()> {
+    let input = input(
+        tokenfolk_gate_context(),
+        gate_configuration_ids(
+            r#"["gid://shopify/GateConfiguration/1", "gid://shopify/GateConfiguration/3"]"#,
+        ),
+    );
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_covering_a_single_gate_configuration_in_an_array() -> Result<()> {
+    let input = input(
+        valid_gate_context(),
+        gate_configuration_ids(r#"["gid://shopify/GateConfiguration/1"]"#),
+    );
+
+    assert_eq!(function(input)?, snowdevil_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_malformed_gate_configuration_ids() -> Result<()> {
+    let input = input(
+        valid_gate_context(),
+        gate_configuration_ids(r#"["gid://shopify/GateConfiguration/1""#),
+    );
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+// Gates the Snowdevil product with the Tokenfolk gate configuration as well
+fn with_overlapping_gates(mut input: input::ResponseData) -> input::ResponseData {
+    if let input::InputCartLinesMerchandise::ProductVariant(variant) =
+        &mut input.cart.lines[0].merchandise
+    {
+        variant.product.gates.push(
+            serde_json::from_value(serde_json::json!({
+                "id": "gid://shopify/GateSubject/5",
+                "configuration": {
+                    "id": "gid://shopify/GateConfiguration/2",
+                    "handle": "tokengating-example-app",
+                    "metafield": {
+                        "value": "{\"name\":\"Tokenfolk discount\",\"type\":\"discount\",\"discount\":{\"type\":\"amount\",\"value\": \"10\"}}"
+                    }
+                }
+            }))
+            .unwrap(),
+        );
+    }
+
+    input
+}
+
+fn overlapping_gates_input(configuration_value: &str) -> input::ResponseData {
+    let attribute = gate_context_from_json(serde_json::json!([
+        {
+            "id": "gid://shopify/GateConfiguration/1",
+            "hmac": "bd3862797c3e532f9f07e6672192d46792ee3591a0c7fe279e14d971eb541b37"
+        },
+        {
+            "id": "gid://shopify/GateConfiguration/2",
+            "hmac": "f095024f57f4642cad6c442b954dd3bd24e4cdc2c180209cda3f5cf2a0c28703"
+        }
+    ]));
+    let mut input = with_overlapping_gates(input(
+        attribute,
+        gate_configuration_ids(
+            r#"["gid://shopify/GateConfiguration/2", "gid://shopify/GateConfiguration/1"]"#,
+        ),
+    ));
+    input.discount_node.configuration = configuration(configuration_value);
+
+    input
+}
+
+fn discounted_variants(result: &crate::output::FunctionResult) -> Vec<(&str, Vec<&str>)> {
+    result
+        .discounts
+        .iter()
+        .map(|discount| {
+            let variants = discount
+                .targets
+                .iter()
+                .map(|target| match target {
+                    crate::output::Target::ProductVariant(target) => target.id.as_str(),
+                })
+                .collect();
+            (discount.message.as_deref().unwrap_or_default(), variants)
+        })
+        .collect()
+}
+
+#[test]
+fn test_overlapping_gates_are_best_for_customer_by_default() -> Result<()> {
+    let input = overlapping_gates_input("{\"allowContextsWithoutAddress\": true}");
+
+    let result = function(input)?;
+
+    assert_eq!(
+        discounted_variants(&result),
+        vec![
+            ("Snowdevil discount", vec!["gid://shopify/ProductVariant/1"]),
+            (
+                "Tokenfolk discount",
+                vec![
+                    "gid://shopify/ProductVariant/2",
+                    "gid://shopify/ProductVariant/3"
+                ]
+            ),
+        ]
+    );
+    assert_eq!(
+        result.discount_application_strategy,
+        crate::output::DiscountApplicationStrategy::MAXIMUM
+    );
+    Ok(())
+}
+
+#[test]
+fn test_overlapping_gates_are_best_for_customer_on_cheap_lines() -> Result<()> {
+    let mut input = overlapping_gates_input(
+        "{\"allowContextsWithoutAddress\": true, \"gatePrecedence\": \"bestForCustomer\"}",
+    );
+    // 25% of 20 is less than the 10 off of the Tokenfolk reaction
+    input.cart.lines[0].cost.amount_per_quantity.amount = "20.0".to_string();
+
+    let result = function(input)?;
+
+    assert_eq!(
+        discounted_variants(&result),
+        vec![(
+            "Tokenfolk discount",
+            vec![
+                "gid://shopify/ProductVariant/1",
+                "gid://shopify/ProductVariant/2",
+                "gid://shopify/ProductVariant/3"
+            ]
+        )]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_overlapping_gates_by_priority() -> Result<()> {
+    let input = overlapping_gates_input(
+        "{\"allowContextsWithoutAddress\": true, \"gatePrecedence\": \"priority\"}",
+    );
+
+    let result = function(input)?;
+
+    assert_eq!(
+        discounted_variants(&result),
+        vec![(
+            "Tokenfolk discount",
+            vec![
+                "gid://shopify/ProductVariant/1",
+                "gid://shopify/ProductVariant/2",
+                "gid://shopify/ProductVariant/3"
+            ]
+        )]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_overlapping_gates_by_first_match() -> Result<()> {
+    let input = overlapping_gates_input(
+        "{\"allowContextsWithoutAddress\": true, \"gatePrecedence\": \"firstMatch\"}",
+    );
+
+    let result = function(input)?;
+
+    assert_eq!(discounted_variants(&result)[0].0, "Snowdevil discount");
+    assert_eq!(
+        discounted_variants(&result)[0].1,
+        vec!["gid://shopify/ProductVariant/1"]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_decision_trace_of_overlapping_gates() {
+    let input = overlapping_gates_input(
+        "{\"allowContextsWithoutAddress\": true, \"gatePrecedence\": \"priority\", \"trace\": true}",
+    );
+
+    let (_, trace) = evaluate_gates(input);
+    let gates = &trace.unwrap().lines[0].gates;
+
+    assert_eq!(gates[0].reaction, None);
+    assert_eq!(
+        gates[0].error.as_deref(),
+        Some("gid://shopify/GateConfiguration/2 took precedence")
+    );
+    assert_eq!(gates[1].reaction.as_deref(), Some("Tokenfolk discount"));
+    assert_eq!(gates[1].error, None);
+}
+
+#[test]
+fn test_discount_application_strategy_first() -> Result<()> {
+    let mut input = input(valid_gate_context(), discount_gate_configuration_id());
+    input.discount_node.configuration = configuration(
+        "{\"allowContextsWithoutAddress\": true, \"discountApplicationStrategy\": \"first\"}",
+    );
+
+    let result = function(input)?;
+
+    assert_eq!(
+        result.discount_application_strategy,
+        crate::output::DiscountApplicationStrategy::FIRST
+    );
+    assert_eq!(result.discounts, snowdevil_discount().discounts);
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_unknown_discount_application_strategy() -> Result<()> {
+    let mut input = input(valid_gate_context(), discount_gate_configuration_id());
+    input.discount_node.configuration = configuration(
+        "{\"allowContextsWithoutAddress\": true, \"discountApplicationStrategy\": \"all\"}",
+    );
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_missing_gate_configuration_id() -> Result<()> {
+    let mut input = input(valid_gate_context(), None);
+    input.discount_node.metafield = None;
+
+    assert_eq!(function(input)?, no_discount());
+    assert_eq!(
+        parse_gate_configuration_ids_from_metafield(None),
+        Err(GateError::MissingMetafield("gate_configuration_id"))
+    );
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_empty_gate_configuration_id() -> Result<()> {
+    for value in ["", "  "] {
+        let input = input(valid_gate_context(), gate_configuration_ids(value));
+
+        assert_eq!(function(input)?, no_discount());
+        assert_eq!(
+            parse_gate_configuration_ids_from_metafield(gate_configuration_ids(value).as_ref()),
+            Err(GateError::MissingMetafield("gate_configuration_id"))
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_malformed_gate_configuration_id() -> Result<()> {
+    for value in [
+        "GateConfiguration/1",
+        "[]",
+        "[1]",
+        r#"["gid://shopify/GateConfiguration/1", "gid://shopify/Product/1"]"#,
+        r#"{"id": "gid://shopify/GateConfiguration/1"}"#,
+    ] {
+        let input = input(valid_gate_context(), gate_configuration_ids(value));
+
+        assert_eq!(function(input)?, no_discount());
+        assert!(matches!(
+            parse_gate_configuration_ids_from_metafield(gate_configuration_ids(value).as_ref()),
+            Err(GateError::MalformedMetafield("gate_configuration_id", _))
+        ));
+    }
+    Ok(())
+}
+
+const TIERED_REACTION: &str = r#"{"name":"Snowdevil holders","type":"discount","discount":{"type":"tiers","tiers":[{"minTokens":1,"discount":{"type":"percentage","value":10}},{"minTokens":10,"discount":{"type":"percentage","value":25}},{"minTokens":3,"discount":{"type":"percentage","value":"15"}}]}}"#;
+
+fn gate_context_with_token_count(
+    token_count: u64,
+    hmac: &str,
+) -> Option<input::InputCartAttribute> {
+    gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": hmac,
+        "v": 2,
+        "tokenCount": token_count
+    }]))
+}
+
+fn tiered_discount_value(
+    attribute: Option<input::InputCartAttribute>,
+) -> Result<Option<crate::output::Value>> {
+    let input = with_reaction(
+        input(attribute, discount_gate_configuration_id()),
+        TIERED_REACTION,
+    );
+
+    let result = function(input)?;
+    Ok(result
+        .discounts
+        .first()
+        .map(|discount| discount.value.clone()))
+}
+
+fn percentage(value: &str) -> Option<crate::output::Value> {
+    Some(crate::output::Value::Percentage(
+        crate::output::Percentage {
+            value: value.to_string(),
+        },
+    ))
+}
+
+#[test]
+fn test_tiered_discount_by_token_count() -> Result<()> {
+    let tiers = [
+        (
+            1,
+            "3a73dc2c31ec59de66ed563b4ffb39dbd8a23b056ef3422cb40672e6bc3aa4e3",
+            "10",
+        ),
+        (
+            2,
+            "aa143ebc3f0a0328babd79985c0dbcc076e28ce03fc664f75a6f600a4a4656bf",
+            "10",
+        ),
+        (
+            3,
+            "17678af6e7197899523182f5d89ae05cedd5669af79b37695241c76841fa5309",
+            "15",
+        ),
+        (
+            9,
+            "d3c667738ed354b9b27472add5050ade2dd926a5a1e315c842abd5140cc3f784",
+            "15",
+        ),
+        (
+            10,
+            "2278e4f2f35bdeb310d2e9a532d1f0d6d2c959692595f7db4bfafab5cd67f23a",
+            "25",
+        ),
+        (
+            11,
+            "82becedef4472674086b489c0fcb6393f22653e755176337a6c684c02c5b744b",
+            "25",
+        ),
+    ];
+
+    for (token_count, hmac, value) in tiers {
+        assert_eq!(
+            tiered_discount_value(gate_context_with_token_count(token_count, hmac))?,
+            percentage(value),
+            "{} tokens",
+            token_count
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_tiered_discount_below_the_lowest_tier() -> Result<()> {
+    let attribute = gate_context_with_token_count(
+        0,
+        "491002db6ea5c4e234d1a1ea5bbc8aac5219e0202e9e1733de7051cd8f487da7",
+    );
+
+    assert_eq!(tiered_discount_value(attribute)?, None);
+    Ok(())
+}
+
+#[test]
+fn test_tiered_discount_without_token_count() -> Result<()> {
+    assert_eq!(
+        tiered_discount_value(valid_gate_context())?,
+        percentage("10")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_tiered_discount_with_aggregate_gate_context_and_unsigned_token_count() -> Result<()> {
+    let attribute = gate_context_from_json(serde_json::json!([
+        {
+            "ids": ["gid://shopify/GateConfiguration/1"],
+            "hmac": "4bec06e7bad2fec3ebbe1e78fe88e4e46bd71fbab85ad995d702171a746d1fd5",
+            "v": 2
+        },
+        {
+            "id": "gid://shopify/GateConfiguration/1",
+            "hmac": "00",
+            "v": 2,
+            "tokenCount": 1000
+        }
+    ]));
+
+    assert_eq!(tiered_discount_value(attribute)?, percentage("10"));
+    Ok(())
+}
+
+#[test]
+fn test_tiered_discount_with_aggregate_gate_context_and_signed_token_count() -> Result<()> {
+    let attribute = gate_context_from_json(serde_json::json!([
+        {
+            "ids": ["gid://shopify/GateConfiguration/1"],
+            "hmac": "4bec06e7bad2fec3ebbe1e78fe88e4e46bd71fbab85ad995d702171a746d1fd5",
+            "v": 2
+        },
+        {
+            "id": "gid://shopify/GateConfiguration/1",
+            "hmac": "2278e4f2f35bdeb310d2e9a532d1f0d6d2c959692595f7db4bfafab5cd67f23a",
+            "v": 2,
+            "tokenCount": 10
+        }
+    ]));
+
+    assert_eq!(tiered_discount_value(attribute)?, percentage("25"));
+    Ok(())
+}
+
+#[cfg(feature = "personal-sign")]
+#[test]
+fn test_discount_with_token_count_in_personal_sign_gate_context() -> Result<()> {
+    let message = format!(
+        "v2\nid=gid://shopify/GateConfiguration/1\naddress={}\ntokenCount=1000",
+        ALLOWLISTED_WALLET
+    );
+    let attribute = gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "personalSign": personal_sign(&wallet_signing_key(1), &message),
+        "v": 2,
+        "address": ALLOWLISTED_WALLET,
+        "tokenCount": 1000
+    }]));
+    let mut input = input(attribute, discount_gate_configuration_id());
+    input.discount_node.secret_key = None;
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_discount_with_token_count_in_merkle_proof_for_buyer() -> Result<()> {
+    let leaf = "gid://shopify/Customer/3";
+    let tree = merkle::MerkleTree::from_csv(MERKLE_CUSTOMERS_CSV, MerkleLeaves::Customer);
+    let mut input = customer_merkle_input(leaf, leaf);
+    input.cart.attribute = merkle_gate_context(
+        leaf,
+        &tree.proof(leaf).unwrap(),
+        serde_json::json!({ "v": 2, "tokenCount": 1000 }),
+    );
+
+    assert_eq!(function(input)?, no_discount());
+    Ok(())
+}
+
+#[test]
+fn test_tiered_discount_with_tampered_token_count() -> Result<()> {
+    let attribute = gate_context_with_token_count(
+        10,
+        "17678af6e7197899523182f5d89ae05cedd5669af79b37695241c76841fa5309",
+    );
+
+    assert_eq!(tiered_discount_value(attribute)?, None);
+    Ok(())
+}
+
+#[test]
+fn test_token_count_needs_a_version_2_message() -> Result<()> {
+    let attribute = gate_context_from_json(serde_json::json!([{
+        "id": "gid://shopify/GateConfiguration/1",
+        "hmac": "bd3862797c3e532f9f07e6672192d46792ee3591a0c7fe279e14d971eb541b37",
+        "tokenCount": 10
+    }]));
+
+    assert_eq!(tiered_discount_value(attribute)?, None);
+    Ok(())
+}
+
+#[test]
+fn test_aggregate_gate_context_with_token_count() -> Result<()> {
+    let attribute = gate_context_from_json(serde_json::json!([{
+        "ids": [
+            "gid://shopify/GateConfiguration/1",
+            "gid://shopify/GateConfiguration/2"
+        ],
+        "hmac": "34321ef227d1fb908e6e81dee59bd6959df9e19e4e722d2c4260b318b61f8c59",
+        "v": 2,
+        "tokenCount": 3
+    }]));
+
+    assert_eq!(tiered_discount_value(attribute)?, None);
+    Ok(())
+}
+
+#[test]
+fn test_invalid_tiered_reactions() {
+    let reactions = [
+        r#"{"name":"Tiers","discount":{"type":"tiers","tiers":[]}}"#,
+        r#"{"name":"Tiers","discount":{"type":"tiers","tiers":[{"minTokens":1,"discount":{"type":"percentage","value":"ten"}}]}}"#,
+        r#"{"name":"Tiers","discount":{"type":"tiers","tiers":[{"minTokens":1,"discount":{"type":"tiers","tiers":[]}}]}}"#,
+        r#"{"name":"Tiers","discount":{"type":"tiers","tiers":[{"minTokens":-1,"discount":{"type":"percentage","value":10}}]}}"#,
+    ];
+
+    for reaction in reactions {
+        let metafield =
+            input::InputCartLinesMerchandiseOnProductVariantProductGatesConfigurationMetafield {
+                value: reaction.to_string(),
+            };
+
+        assert!(matches!(
+            parse_gate_reaction_from_metafield(Some(&metafield)),
+            Err(GateError::InvalidReaction(_))
+        ));
+    }
+}
+
+const QUANTITY_TIERED_REACTION: &str = r#"{"name":"Snowdevil discount","type":"discount","discount":{"type":"percentage","value":10},"quantityTiers":[{"minQuantity":3,"discount":{"type":"percentage","value":20}}]}"#;
+
+fn quantity_tiered_discount_value(quantity: i64) -> Result<Option<crate::output::Value>> {
+    let mut input = with_reaction(
+        input(valid_gate_context(), discount_gate_configuration_id()),
+        QUANTITY_TIERED_REACTION,
+    );
+    input.cart.lines[0].quantity = quantity;
+
+    let result = function(input)?;
+    Ok(result
+        .discounts
+        .first()
+        .map(|discount| discount.value.clone()))
+}
+
+#[test]
+fn test_quantity_tiered_discount() -> Result<()> {
+    for (quantity, value) in [(1, "10"), (2, "10"), (3, "20"), (5, "20")] {
+        assert_eq!(
+            quantity_tiered_discount_value(quantity)?,
+            percentage(value),
+            "{} units",
+            quantity
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_quantity_tiered_discount_per_line() -> Result<()> {
+    let mut input = with_line_reaction(
+        with_line_reaction(
+            input(tokenfolk_gate_context(), tokenfolk_gate_configuration_id()),
+            1,
+            QUANTITY_TIERED_REACTION,
+        ),
+        2,
+        QUANTITY_TIERED_REACTION,
+    );
+    input.cart.lines[2].quantity = 3;
+
+    let result = function(input)?;
+    let values: Vec<_> = result
+        .discounts
+        .iter()
+        .map(|discount| Some(discount.value.clone()))
+        .collect();
+
+    assert_eq!(values, vec![percentage("10"), percentage("20")]);
+    Ok(())
+}
+
+#[test]
+fn test_quantity_tiers_with_token_tiers() -> Result<()> {
+    let reaction = r#"{"name":"Snowdevil holders","discount":{"type":"percentage","value":10},"quantityTiers":[{"minQuantity":2,"discount":{"type":"tiers","tiers":[{"minTokens":1,"discount":{"type":"percentage","value":20}},{"minTokens":3,"discount":{"type":"percentage","value":30}}]}}]}"#;
+    let attribute = gate_context_with_token_count(
+        3,
+        "17678af6e7197899523182f5d89ae05cedd5669af79b37695241c76841fa5309",
+    );
+    let mut input = with_reaction(input(attribute, discount_gate_configuration_id()), reaction);
+    input.cart.lines[0].quantity = 2;
+
+    let result = function(input)?;
+
+    assert_eq!(Some(result.discounts[0].value.clone()), percentage("30"));
+    Ok(())
+}
+
+#[test]
+fn test_invalid_quantity_tier() {
+    let metafield =
+        input::InputCartLinesMerchandiseOnProductVariantProductGatesConfigurationMetafield {
+            value: r#"{"name":"Snowdevil discount","discount":{"type":"percentage","value":10},"quantityTiers":[{"minQuantity":3,"discount":{"type":"percentage","value":"twenty"}}]}"#.to_string(),
+        };
+
+    assert!(matches!(
+        parse_gate_reaction_from_metafield(Some(&metafield)),
+        Err(GateError::InvalidReaction(_))
+    ));
+}
+
+const CAPPED_REACTION: &str = r#"{"name":"Snowdevil drop","type":"discount","discount":{"type":"percentage","value":25},"maxDiscountedUnits":2}"#;
+
+fn capped_target_quantity(quantity: i64) -> Result<Option<i64>> {
+    let mut input = with_reaction(
+        input(valid_gate_context(), discount_gate_configuration_id()),
+        CAPPED_REACTION,
+    );
+    input.cart.lines[0].quantity = quantity;
+
+    let result = function(input)?;
+    match &result.discounts[0].targets[0] {
+        crate::output::Target::ProductVariant(target) => Ok(target.quantity),
+    }
+}
+
+#[test]
+fn test_max_discounted_units() -> Result<()> {
+    assert_eq!(capped_target_quantity(1)?, Some(1));
+    assert_eq!(capped_target_quantity(2)?, Some(2));
+    assert_eq!(capped_target_quantity(5)?, Some(2));
+    Ok(())
+}
+
+#[test]
+fn test_max_discounted_units_in_best_for_customer_precedence() -> Result<()> {
+    // 25% of a single unit of 20 is less than 10 off, 25% of all three units is not
+    let mut input = with_line_reaction(
+        overlapping_gates_input("{\"allowContextsWithoutAddress\": true}"),
+        0,
+        r#"{"name":"Snowdevil discount","discount":{"type":"percentage","value":25},"maxDiscountedUnits":1}"#,
+    );
+    input.cart.lines[0].quantity = 3;
+    input.cart.lines[0].cost.amount_per_quantity.amount = "20.0".to_string();
+
+    let result = function(input)?;
+
+    assert_eq!(
+        discounted_variants(&result)[0],
+        (
+            "Tokenfolk discount",
+            vec![
+                "gid://shopify/ProductVariant/1",
+                "gid://shopify/ProductVariant/2",
+                "gid://shopify/ProductVariant/3"
+            ]
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn test_invalid_max_discounted_units() {
+    let metafield =
+        input::InputCartLinesMerchandiseOnProductVariantProductGatesConfigurationMetafield {
+            value: r#"{"name":"Snowdevil drop","discount":{"type":"percentage","value":25},"maxDiscountedUnits":0}"#.to_string(),
+        };
+
+    assert!(matches!(
+        parse_gate_reaction_from_metafield(Some(&metafield)),
+        Err(GateError::InvalidReaction(_))
+    ));
+}
+
+fn amount_reaction(applies_to_each_item: Option<bool>) -> String {
+    serde_json::json!({
+        "name": "Tokenfolk discount",
+        "type": "discount",
+        "discount": {
+            "type": "amount",
+            "value": "10",
+            "appliesToEachItem": applies_to_each_item
+        }
+    })
+    .to_string()
+}
+
+#[test]
+fn test_discount_as_amount_off_each_item() -> Result<()> {
+    for applies_to_each_item in [Some(true), Some(false), None] {
+        let input = with_line_reaction(
+            input(tokenfolk_gate_context(), tokenfolk_gate_configuration_id()),
+            1,
+            &amount_reaction(applies_to_each_item),
+        );
+
+        let result = function(input)?;
+
+        assert_eq!(
+            result.discounts[0].value,
+            crate::output::Value::FixedAmount(crate::output::FixedAmount {
+                amount: "10".to_string(),
+                applies_to_each_item,
+            })
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_amount_off_each_item_in_best_for_customer_precedence() -> Result<()> {
+    // 25% of three units of 20 is more than 10 off once, but less than 10 off each
+    for (applies_to_each_item, message) in [
+        (Some(false), "Snowdevil discount"),
+        (Some(true), "Tokenfolk discount"),
+    ] {
+        let mut input = overlapping_gates_input("{\"allowContextsWithoutAddress\": true}");
+        input.cart.lines[0].quantity = 3;
+        input.cart.lines[0].cost.amount_per_quantity.amount = "20.0".to_string();
+        if let input::InputCartLinesMerchandise::ProductVariant(variant) =
+            &mut input.cart.lines[0].merchandise
+        {
+            variant.product.gates[1].configuration.metafield = Some(
+                input::InputCartLinesMerchandiseOnProductVariantProductGatesConfigurationMetafield {
+                    value: amount_reaction(applies_to_each_item),
+                },
+            );
+        }
+
+        let result = function(input)?;
+        let discount = result
+            .discounts
+            .iter()
+            .find(|discount| {
+                discount.targets.iter().any(|target| match target {
+                    crate::output::Target::ProductVariant(target) => {
+                        target.id == "gid://shopify/ProductVariant/1"
+                    }
+                })
+            })
+            .unwrap();
+
+        assert_eq!(discount.message.as_deref(), Some(message));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_discount_values_are_passed_on_as_written() -> Result<()> {
+    let cases = [
+        ("5.10", Some("5.10")),
+        ("\"5.10\"", Some("5.10")),
+        ("5", Some("5")),
+        ("1e1", None),
+        ("-5", None),
+        ("\" 5\"", None),
+    ];
+
+    for (value, amount) in cases {
+        let input = with_reaction(
+            input(valid_gate_context(), discount_gate_configuration_id()),
+            &format!(
+                r#"{{"name":"Holder discount","discount":{{"type":"amount","value":{}}}}}"#,
+                value
+            ),
+        );
+
+        let result = function(input)?;
+
+        assert_eq!(
+            result
+                .discounts
+                .first()
+                .map(|discount| discount.value.clone()),
+            amount.map(
+                |amount| crate::output::Value::FixedAmount(crate::output::FixedAmount {
+                    amount: amount.to_string(),
+                    applies_to_each_item: None,
+                })
+            ),
+            "{}",
+            value
+        );
+    }
+    Ok(())
+}
+
+fn target_price_discount_value(
+    target_price: &str,
+    unit_price: &str,
+    currency_code: input::CurrencyCode,
+    presentment_currency_rate: &str,
+) -> Result<Option<crate::output::Value>> {
+    let reaction = serde_json::json!({
+        "name": "Holder price",
+        "type": "discount",
+        "discount": { "type": "targetPrice", "value": target_price }
+    });
+    let mut input = with_reaction(
+        input(valid_gate_context(), discount_gate_configuration_id()),
+        &reaction.to_string(),
+    );
+    input.cart.lines[0].cost.amount_per_quantity.amount = unit_price.to_string();
+    input.cart.lines[0].cost.amount_per_quantity.currency_code = currency_code;
+    input.presentment_currency_rate = presentment_currency_rate.to_string();
+
+    let result = function(input)?;
+    Ok(result
+        .discounts
+        .first()
+        .map(|discount| discount.value.clone()))
+}
+
+fn amount_off_each_item(amount: &str) -> crate::output::Value {
+    crate::output::Value::FixedAmount(crate::output::FixedAmount {
+        amount: amount.to_string(),
+        applies_to_each_item: Some(true),
+    })
+}
+
+#[test]
+fn test_target_price_discount() -> Result<()> {
+    let cases = [
+        (
+            "79.99",
+            "100.0",
+            input::CurrencyCode::USD,
+            "1.0",
+            Some("20.01"),
+        ),
+        ("100", "100.0", input::CurrencyCode::USD, "1.0", None),
+        ("125", "100.0", input::CurrencyCode::USD, "1.0", None),
+        // 99.996 at 1 is rounded to 100.00
+        ("99.996", "100.0", input::CurrencyCode::USD, "1.0", None),
+        // 19.99 at 0.92345 is 18.4597655 EUR, rounded to 18.46
+        (
+            "19.99",
+            "25.00",
+            input::CurrencyCode::EUR,
+            "0.92345",
+            Some("6.54"),
+        ),
+        (
+            "8",
+            "1500.0",
+            input::CurrencyCode::JPY,
+            "150.0",
+            Some("300"),
+        ),
+        // 8.01 at 149.87 is 1200.4587 JPY, rounded to 1200
+        (
+            "8.01",
+            "1500.0",
+            input::CurrencyCode::JPY,
+            "149.87",
+            Some("300"),
+        ),
+        // 25.5 at 0.3075 is 7.84125 KWD, rounded to 7.841
+        (
+            "25.5",
+            "10.000",
+            input::CurrencyCode::KWD,
+            "0.3075",
+            Some("2.159"),
+        ),
+    ];
+
+    for (target_price, unit_price, currency_code, rate, amount) in cases {
+        assert_eq!(
+            target_price_discount_value(target_price, unit_price, currency_code, rate)?,
+            amount.map(amount_off_each_item),
+            "{} from {} at {}",
+            target_price,
+            unit_price,
+            rate
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_target_price_as_a_number() -> Result<()> {
+    let input = with_reaction(
+        input(valid_gate_context(), discount_gate_configuration_id()),
+        r#"{"name":"Holder price","discount":{"type":"targetPrice","value":79.99}}"#,
+    );
+
+    let result = function(input)?;
+
+    assert_eq!(result.discounts[0].value, amount_off_each_item("20.01"));
+    Ok(())
+}
+
+#[test]
+fn test_target_price_with_malformed_amounts() -> Result<()> {
+    let mut input = with_reaction(
+        input(valid_gate_context(), discount_gate_configuration_id()),
+        r#"{"name":"Holder price","discount":{"type":"targetPrice","value":"79.99"}}"#,
+    );
+    input.presentment_currency_rate = "-1".to_string();
+
+    assert_eq!(function(input)?, no_discount());
+
+    let metafield =
+        input::InputCartLinesMerchandiseOnProductVariantProductGatesConfigurationMetafield {
+            value: r#"{"name":"Holder price","discount":{"type":"targetPrice","value":"cheap"}}"#
+                .to_string(),
+        };
+    assert!(matches!(
+        parse_gate_reaction_from_metafield(Some(&metafield)),
+        Err(GateError::InvalidReaction(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_amount_off_to_reach() {
+    let cases = [
+        ("19.99", "9.995", "1", 2, Some("9.99")),
+        ("19.99", "9.994", "1", 2, Some("10.00")),
+        ("19.99", "9.9949", "1", 2, Some("10.00")),
+        ("19.99", "9.985", "1", 2, Some("10.00")),
+        ("19.99", "9.98", "1", 2, Some("10.01")),
+        ("0.5", "0", "1", 0, Some("1")),
+        (" 12 ", "2", "1.5", 2, Some("9.00")),
+        ("1e3", "2", "1", 2, None),
+        ("12", "-2", "1", 2, None),
+        ("12", "2.", "1", 2, Some("10.00")),
+        ("12", ".5", "1", 2, None),
+    ];
+
+    for (unit_price, target_price, rate, decimals, amount) in cases {
+        assert_eq!(
+            money::amount_off_to_reach(unit_price, target_price, rate, decimals).as_deref(),
+            amount,
+            "{} to {} at {}",
+            unit_price,
+            target_price,
+            rate
+        );
+    }
+}
+
+#[test]
+fn test_target_price_at_the_unit_price_does_not_take_precedence() -> Result<()> {
+    let input = with_reaction(
+        overlapping_gates_input(
+            r#"{"allowContextsWithoutAddress": true, "gatePrecedence": "firstMatch", "trace": true}"#,
+        ),
+        r#"{"name":"Holder price","discount":{"type":"targetPrice","value":"125"}}"#,
+    );
+
+    let (result, trace) = evaluate_gates(input);
+
+    assert_eq!(
+        discounted_variants(&result),
+        vec![(
+            "Tokenfolk discount",
+            vec![
+                "gid://shopify/ProductVariant/1",
+                "gid://shopify/ProductVariant/2",
+                "gid://shopify/ProductVariant/3"
+            ]
+        )]
+    );
+    assert_eq!(
+        trace.unwrap().lines[0].gates[0].error.as_deref(),
+        Some("unit price 100.0 is already at the target price")
+    );
+    Ok(())
+}
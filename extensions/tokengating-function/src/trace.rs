@@ -0,0 +1,131 @@
+// Opt-in record of how the function got to its result, for when holders report
+// that they did not get their discount. Function logs are cut off at about 1 kB,
+// so it is written to stderr as lines of JSON of at most `MAX_RECORD_LENGTH` bytes
+// each, rather than one line for the whole cart. The first sums the cart up,
+// `{"decisionTrace":{"lines":4,"error":null}}`, and each cart line follows in
+// records of its own, `{"decisionTrace":{"line":0,"variantId":...,"gates":[...]}}`.
+// A log that is cut off only loses its last records, never the ones before them.
+//
+// Each cart line with a product variant lists every gate subject of its product:
+// whether the gate is covered by this discount, whether the gate context holds an
+// item for it, whether that item verified and which reaction it was given. Lines
+// with more gates than fit in one record are split over several records with the
+// same `line`. A gate context, gate configuration IDs or configuration that cannot
+// be read leave a trace without lines and with the reason as its `error`.
+use serde::Serialize;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct DecisionTrace {
+    pub lines: Vec<LineTrace>,
+    // Why no gate could be unlocked at all, in which case no line was looked at
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LineTrace {
+    pub variant_id: String,
+    pub gates: Vec<GateTrace>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GateTrace {
+    pub gate_subject_id: String,
+    pub gate_configuration_id: String,
+    pub covered_by_discount: bool,
+    pub context_item_found: bool,
+    pub signature_verified: bool,
+    // The name of the reaction that was applied
+    pub reaction: Option<String>,
+    // Why an unlocked gate still gave no discount
+    pub error: Option<String>,
+}
+
+pub const MAX_RECORD_LENGTH: usize = 800;
+
+// Reaction names and errors are cut to this many characters, so that a record with
+// a single gate always fits
+const MAX_TEXT_LENGTH: usize = 100;
+
+// Wraps each record under a key that can be searched for in the logs
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TraceLog<T> {
+    decision_trace: T,
+}
+
+#[derive(Serialize)]
+struct TraceSummary<'a> {
+    lines: usize,
+    error: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LineRecord<'a> {
+    line: usize,
+    variant_id: &'a str,
+    gates: &'a [GateTrace],
+}
+
+impl DecisionTrace {
+    pub fn to_log_records(&self) -> Vec<String> {
+        let mut records = vec![to_json(TraceSummary {
+            lines: self.lines.len(),
+            error: self.error.as_deref().map(shortened),
+        })];
+
+        for (index, line) in self.lines.iter().enumerate() {
+            let line_record = |gates: &[GateTrace]| {
+                to_json(LineRecord {
+                    line: index,
+                    variant_id: &line.variant_id,
+                    gates,
+                })
+            };
+            let mut gates: Vec<GateTrace> = vec![];
+            for gate in &line.gates {
+                gates.push(gate.shortened());
+                if gates.len() > 1 && line_record(&gates).len() > MAX_RECORD_LENGTH {
+                    let gate = gates.pop();
+                    records.push(line_record(&gates));
+                    gates = gate.into_iter().collect();
+                }
+            }
+            records.push(line_record(&gates));
+        }
+
+        records
+    }
+}
+
+impl GateTrace {
+    fn shortened(&self) -> GateTrace {
+        GateTrace {
+            reaction: self
+                .reaction
+                .as_deref()
+                .map(|text| shortened(text).to_string()),
+            error: self
+                .error
+                .as_deref()
+                .map(|text| shortened(text).to_string()),
+            ..self.clone()
+        }
+    }
+}
+
+fn shortened(text: &str) -> &str {
+    match text.char_indices().nth(MAX_TEXT_LENGTH) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+fn to_json<T: Serialize>(record: T) -> String {
+    serde_json::to_string(&TraceLog {
+        decision_trace: record,
+    })
+    .unwrap_or_default()
+}
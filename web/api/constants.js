@@ -1,2 +1,34 @@
 export const myHandle = "tokengating-example-app";
 export const myAppMetafieldNamespace = myHandle;
+// Reserved to this app, so that other apps that can read discounts and validations
+// cannot read the signing secret stored there
+export const myAppReservedMetafieldNamespace = `$app:${myHandle}`;
+
+// Shared with the functions through the app-reserved `secret_key` metafield on the
+// discount node
+export const gateContextSecretKey = process.env.GATE_CONTEXT_SECRET_KEY;
+// Optional identifier of the key above, sent as `kid` so the functions can keep
+// verifying it from a keyring after it is rotated out
+export const gateContextKeyId = process.env.GATE_CONTEXT_KEY_ID;
+// Set to "hkdf" to sign each gate's contexts with a key derived from the secret
+// and the gate configuration ID, so a leaked gate key only unlocks that gate
+export const gateContextKeyDerivation = process.env.GATE_CONTEXT_KEY_DERIVATION;
+// Optionally bind gate contexts to the buyer, either "customer" or "email"
+export const gateContextBuyerBinding = process.env.GATE_CONTEXT_BUYER_BINDING;
+// Optional lifetime of gate contexts. When set, the app keeps the `reference_time`
+// metafield that the functions compare expiry against up to date, see
+// reference-time.js.
+export const gateContextTtlSeconds = parseInt(process.env.GATE_CONTEXT_TTL_SECONDS, 10) || undefined;
+// Optional PEM encoded Ed25519 private key. When set, gate contexts are signed with
+// it instead of the shared secret and the functions only get the public key. The
+// discount function then has to be built with its `ed25519` feature.
+export const gateContextPrivateKey = process.env.GATE_CONTEXT_ED25519_PRIVATE_KEY;
+
+if (!gateContextSecretKey && !gateContextPrivateKey) {
+  console.error(`
+    ************************************************************
+    You must set the GATE_CONTEXT_SECRET_KEY or GATE_CONTEXT_ED25519_PRIVATE_KEY environment variable.
+    Gate contexts cannot be signed and the function will keep every gate locked.
+    ************************************************************
+  `);
+}
@@ -1,4 +1,16 @@
-import { myAppMetafieldNamespace } from "./constants.js";
+import { createPublicKey } from "crypto";
+
+import {
+  gateContextBuyerBinding,
+  gateContextKeyDerivation,
+  gateContextKeyId,
+  gateContextPrivateKey,
+  gateContextSecretKey,
+  gateContextTtlSeconds,
+  myAppMetafieldNamespace,
+  myAppReservedMetafieldNamespace,
+} from "./constants.js";
+import { referenceTimeMetafield } from "./reference-time.js";
 
 const YOUR_FUNCTION_ID = "YOUR_FUNCTION_ID";
 console.log(`Loaded function id ${YOUR_FUNCTION_ID}`);
@@ -45,6 +57,17 @@ export const createAutomaticDiscount = async (client, gateConfiguration) => {
                 namespace: myAppMetafieldNamespace,
                 type: "single_line_text_field",
                 value: gateConfiguration.id
+              },
+              ...signingKeyMetafields(),
+              // Refreshed from then on by `scheduleReferenceTimeRefresh`
+              ...(gateContextTtlSeconds ? [referenceTimeMetafield()] : []),
+              {
+                key: "configuration",
+                namespace: myAppMetafieldNamespace,
+                type: "json",
+                value: JSON.stringify({
+                  buyerBinding: gateContextBuyerBinding || "none",
+                }),
               }
             ]
           },
@@ -52,3 +75,46 @@ export const createAutomaticDiscount = async (client, gateConfiguration) => {
       },
     });
   }
+
+const signingKeyMetafields = () => {
+  if (gateContextPrivateKey) {
+    // The functions expect the raw 32 bytes of the public key, hex encoded
+    const { x } = createPublicKey(gateContextPrivateKey).export({ format: "jwk" });
+    return [
+      {
+        key: "public_key",
+        namespace: myAppMetafieldNamespace,
+        type: "single_line_text_field",
+        value: Buffer.from(x, "base64url").toString("hex"),
+      },
+    ];
+  }
+
+  return [
+    {
+      key: "secret_key",
+      namespace: myAppReservedMetafieldNamespace,
+      type: "single_line_text_field",
+      value: secretKeyMetafieldValue(),
+    },
+  ];
+};
+
+// A bare secret when there is no key identifier or derivation, otherwise a keyring
+// that older keys can be added to as "retiring" when the secret is rotated
+const secretKeyMetafieldValue = () => {
+  if (!gateContextKeyId && !gateContextKeyDerivation) {
+    return gateContextSecretKey;
+  }
+
+  return JSON.stringify({
+    keys: [
+      {
+        ...(gateContextKeyId && { kid: gateContextKeyId }),
+        secret: gateContextSecretKey,
+        status: "active",
+        ...(gateContextKeyDerivation && { derivation: gateContextKeyDerivation }),
+      },
+    ],
+  });
+};
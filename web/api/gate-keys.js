@@ -0,0 +1,15 @@
+import { hkdfSync } from "crypto";
+
+import { gateContextKeyDerivation } from "./constants.js";
+
+// Must match `GATE_KEY_SALT` in the functions
+const GATE_KEY_SALT = "tokengating-example-app/gate-key";
+
+// Must match `gate_key` in the functions, see test-vectors/gate-keys.json
+export function gateKey(secret, gateConfigurationId) {
+  return Buffer.from(hkdfSync("sha256", secret, GATE_KEY_SALT, gateConfigurationId, 32));
+}
+
+export function hmacKey(secret, gateConfigurationId) {
+  return gateContextKeyDerivation === "hkdf" ? gateKey(secret, gateConfigurationId) : secret;
+}
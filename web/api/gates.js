@@ -14,7 +14,7 @@ export async function getContractAddressesFromGate({ shopDomain, productGid }) {
   }
 }
 
-async function getSessionByShopDomain({ shopDomain }) {
+export async function getSessionByShopDomain({ shopDomain }) {
   try {
     const sessions = await shopify.config.sessionStorage.findSessionsByShop(
       shopDomain
@@ -0,0 +1,46 @@
+import sqlite3 from "sqlite3";
+
+import { DB_PATH } from "../shopify.js";
+
+// The session storage cannot list the shops it holds sessions for, so the app keeps
+// its own list of the shops that used it, for jobs that run outside of a request
+const db = new sqlite3.Database(DB_PATH);
+const ready = run("CREATE TABLE IF NOT EXISTS installed_shops (shop TEXT PRIMARY KEY)");
+
+// Runs for every authenticated request, so shops that installed the app before the
+// list existed are added the next time the app is opened
+export async function rememberInstalledShop(_req, res, next) {
+  try {
+    await ready;
+    await run("INSERT OR IGNORE INTO installed_shops (shop) VALUES (?)", [
+      res.locals.shopify.session.shop,
+    ]);
+  } catch (error) {
+    console.error("Failed to remember the installed shop:", error.message);
+  }
+  next();
+}
+
+export async function forgetInstalledShop(shopDomain) {
+  await ready;
+  await run("DELETE FROM installed_shops WHERE shop = ?", [shopDomain]);
+}
+
+export async function installedShopDomains() {
+  await ready;
+  return new Promise((resolve, reject) => {
+    db.all("SELECT shop FROM installed_shops", (error, rows) => {
+      if (error) {
+        reject(error);
+      } else {
+        resolve(rows.map((row) => row.shop));
+      }
+    });
+  });
+}
+
+function run(sql, params = []) {
+  return new Promise((resolve, reject) => {
+    db.run(sql, params, (error) => (error ? reject(error) : resolve()));
+  });
+}
@@ -0,0 +1,122 @@
+import { myAppMetafieldNamespace } from "./constants.js";
+
+// metafieldsSet takes at most 25 metafields at a time
+const METAFIELDS_SET_LIMIT = 25;
+
+const GATED_DISCOUNT_NODES_QUERY = `
+  query GatedDiscountNodes($after: String) {
+    discountNodes(first: 100, after: $after, query: "type:app") {
+      nodes {
+        id
+        gateConfigurationId: metafield(namespace: "${myAppMetafieldNamespace}",
+          key: "gate_configuration_id") {
+            value
+        }
+        revokedNonces: metafield(namespace: "${myAppMetafieldNamespace}",
+          key: "revoked_nonces") {
+            value
+        }
+      }
+      pageInfo {
+        hasNextPage
+        endCursor
+      }
+    }
+  }
+`;
+
+const APP_VALIDATIONS_QUERY = `
+  query AppValidations($after: String) {
+    currentAppInstallation {
+      app {
+        id
+      }
+    }
+    validations(first: 100, after: $after) {
+      nodes {
+        id
+        shopifyFunction {
+          app {
+            id
+          }
+        }
+        revokedNonces: metafield(namespace: "${myAppMetafieldNamespace}",
+          key: "revoked_nonces") {
+            value
+        }
+      }
+      pageInfo {
+        hasNextPage
+        endCursor
+      }
+    }
+  }
+`;
+
+const SET_METAFIELDS_MUTATION = `
+  mutation SetMetafields($metafields: [MetafieldsSetInput!]!) {
+    metafieldsSet(metafields: $metafields) {
+      userErrors {
+        field
+        message
+      }
+    }
+  }
+`;
+
+// The discounts created by this app are the ones that cover a gate configuration
+async function gatedDiscountNodes(client) {
+  const gatedNodes = [];
+  let after = null;
+  do {
+    const response = await client.query({
+      data: { query: GATED_DISCOUNT_NODES_QUERY, variables: { after } },
+    });
+    const { nodes, pageInfo } = response.body.data.discountNodes;
+    gatedNodes.push(...nodes.filter((node) => node.gateConfigurationId));
+    after = pageInfo.hasNextPage ? pageInfo.endCursor : null;
+  } while (after);
+
+  return gatedNodes;
+}
+
+// The checkout validations that run a function of this app
+async function appValidations(client) {
+  const validations = [];
+  let after = null;
+  do {
+    const response = await client.query({
+      data: { query: APP_VALIDATIONS_QUERY, variables: { after } },
+    });
+    const { currentAppInstallation, validations: page } = response.body.data;
+    validations.push(
+      ...page.nodes.filter(
+        (node) => node.shopifyFunction.app.id === currentAppInstallation.app.id
+      )
+    );
+    after = page.pageInfo.hasNextPage ? page.pageInfo.endCursor : null;
+  } while (after);
+
+  return validations;
+}
+
+// Everything that verifies gate contexts and reads the app's metafields to do so:
+// the discounts created by this app and the validations running its function
+export async function gateContextVerifiers(client) {
+  return [...(await gatedDiscountNodes(client)), ...(await appValidations(client))];
+}
+
+export async function setMetafields(client, metafields) {
+  const userErrors = [];
+  for (let i = 0; i < metafields.length; i += METAFIELDS_SET_LIMIT) {
+    const response = await client.query({
+      data: {
+        query: SET_METAFIELDS_MUTATION,
+        variables: { metafields: metafields.slice(i, i + METAFIELDS_SET_LIMIT) },
+      },
+    });
+    userErrors.push(...response.body.data.metafieldsSet.userErrors);
+  }
+
+  return userErrors;
+}
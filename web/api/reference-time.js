@@ -0,0 +1,61 @@
+import shopify from "../shopify.js";
+import { gateContextTtlSeconds, myAppMetafieldNamespace } from "./constants.js";
+import { getSessionByShopDomain } from "./gates.js";
+import { installedShopDomains } from "./installed-shops.js";
+import { gateContextVerifiers, setMetafields } from "./metafields.js";
+
+// Functions have no clock, so they compare `issuedAt` and `expiresAt` against the
+// `reference_time` metafield of the discount or validation instead. It has to be
+// refreshed well within the functions' `clockSkewSeconds`, 300 by default.
+const REFERENCE_TIME_REFRESH_SECONDS = 60;
+
+export function referenceTimeMetafield() {
+  return {
+    key: "reference_time",
+    namespace: myAppMetafieldNamespace,
+    type: "number_integer",
+    value: `${Math.floor(Date.now() / 1000)}`,
+  };
+}
+
+// Gate contexts only expire when the app gives them a lifetime, so the reference
+// time is only kept up to date then
+export function scheduleReferenceTimeRefresh() {
+  if (!gateContextTtlSeconds) {
+    return;
+  }
+
+  const refresh = () =>
+    refreshReferenceTime().catch((error) => {
+      console.error("Failed to refresh the reference time:", error.message);
+    });
+  refresh();
+  setInterval(refresh, REFERENCE_TIME_REFRESH_SECONDS * 1000);
+}
+
+async function refreshReferenceTime() {
+  for (const shopDomain of await installedShopDomains()) {
+    try {
+      await refreshShopReferenceTime(shopDomain);
+    } catch (error) {
+      console.error("Failed to refresh the reference time of", shopDomain, error.message);
+    }
+  }
+}
+
+async function refreshShopReferenceTime(shopDomain) {
+  const session = await getSessionByShopDomain({ shopDomain });
+  if (!session) {
+    return;
+  }
+  const client = new shopify.api.clients.Graphql({ session });
+
+  const metafields = (await gateContextVerifiers(client)).map(({ id }) => ({
+    ownerId: id,
+    ...referenceTimeMetafield(),
+  }));
+  const userErrors = await setMetafields(client, metafields);
+  if (userErrors.length > 0) {
+    console.error("Failed to set the reference time of", shopDomain, userErrors);
+  }
+}
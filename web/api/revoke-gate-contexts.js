@@ -0,0 +1,52 @@
+import shopify from "../shopify.js";
+import { myAppMetafieldNamespace } from "./constants.js";
+import { gateContextVerifiers, setMetafields } from "./metafields.js";
+
+// Must match `valid_nonce` in the functions
+const NONCE_PATTERN = /^[A-Za-z0-9_-]{1,64}$/;
+
+// Adds the nonces of leaked gate contexts to the `revoked_nonces` metafield of
+// every discount and validation that verifies this app's gate contexts. The
+// functions look nonces up with a binary search and reject lists that are not
+// sorted, so the list is stored sorted.
+export default async function revokeGateContexts({ session, nonces }) {
+  if (!Array.isArray(nonces) || nonces.length === 0) {
+    throw new Error("No nonces to revoke");
+  }
+  const invalidNonce = nonces.find((nonce) => !NONCE_PATTERN.test(nonce));
+  if (invalidNonce !== undefined) {
+    throw new Error(`${JSON.stringify(invalidNonce)} is not a gate context nonce`);
+  }
+
+  const client = new shopify.api.clients.Graphql({ session });
+  const metafields = (await gateContextVerifiers(client)).map(({ id, revokedNonces }) => ({
+    ownerId: id,
+    key: "revoked_nonces",
+    namespace: myAppMetafieldNamespace,
+    type: "json",
+    value: JSON.stringify(sortedNonces([...parseNonces(revokedNonces), ...nonces])),
+  }));
+  const userErrors = await setMetafields(client, metafields);
+  if (userErrors.length > 0) {
+    throw new Error(`Failed to revoke gate contexts: ${JSON.stringify(userErrors)}`);
+  }
+
+  return { revokedNonces: nonces.length, owners: metafields.length };
+}
+
+function parseNonces(metafield) {
+  if (!metafield) {
+    return [];
+  }
+
+  const nonces = JSON.parse(metafield.value);
+  if (!Array.isArray(nonces)) {
+    throw new Error("The revoked_nonces metafield is not a list");
+  }
+  return nonces;
+}
+
+// Nonces are ASCII, so code unit order is the byte order the functions compare in
+function sortedNonces(nonces) {
+  return [...new Set(nonces)].sort();
+}
@@ -0,0 +1,24 @@
+// Checks the JS signer against the test vectors shared with the functions
+import { createHmac } from "crypto";
+import { readFileSync } from "fs";
+
+import { gateKey } from "./api/gate-keys.js";
+
+const { vectors } = JSON.parse(
+  readFileSync(
+    new URL("../extensions/tokengating-function/test-vectors/gate-keys.json", import.meta.url)
+  )
+);
+
+let failures = 0;
+for (const vector of vectors) {
+  const key = gateKey(vector.secret, vector.gateConfigurationId);
+  const hmac = createHmac("sha256", key).update(vector.message).digest("hex");
+  if (key.toString("hex") !== vector.gateKey || hmac !== vector.hmac) {
+    console.error(`Mismatch for ${vector.gateConfigurationId} signed with ${vector.secret}`);
+    failures++;
+  }
+}
+
+console.log(`${vectors.length - failures} of ${vectors.length} gate key test vectors match`);
+process.exit(failures ? 1 : 0);
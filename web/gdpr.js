@@ -1,5 +1,7 @@
 import { DeliveryMethod } from "@shopify/shopify-api";
 
+import { forgetInstalledShop } from "./api/installed-shops.js";
+
 export default {
   /**
    * Customers can request their data from a store owner. When this happens,
@@ -78,6 +80,7 @@ export default {
       //   "shop_id": 954889,
       //   "shop_domain": "{shop}.myshopify.com"
       // }
+      await forgetInstalledShop(shop);
     },
   },
 };
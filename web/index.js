@@ -8,8 +8,11 @@ import shopify from "./shopify.js";
 import createGate from "./api/create-gate.js";
 import retrieveGates from "./api/retrieve-gates.js";
 import deleteGate from "./api/delete-gate.js";
+import revokeGateContexts from "./api/revoke-gate-contexts.js";
 import GDPRWebhookHandlers from "./gdpr.js";
 import { configurePublicApi } from "./public-api.js";
+import { scheduleReferenceTimeRefresh } from "./api/reference-time.js";
+import { rememberInstalledShop } from "./api/installed-shops.js";
 
 const PORT = parseInt(process.env.BACKEND_PORT || process.env.PORT, 10);
 
@@ -36,7 +39,7 @@ app.use(express.json());
 configurePublicApi(app);
 
 // All endpoints after this point will require an active session
-app.use("/api/*", shopify.validateAuthenticatedSession());
+app.use("/api/*", shopify.validateAuthenticatedSession(), rememberInstalledShop);
 
 app.get("/api/gates", async (_req, res) => {
   try {
@@ -80,6 +83,21 @@ app.delete("/api/gates/:id", async (req, res) => {
   }
 });
 
+app.post("/api/revoked-nonces", async (req, res) => {
+  const { nonces } = req.body;
+
+  try {
+    const response = await revokeGateContexts({
+      session: res.locals.shopify.session,
+      nonces,
+    });
+    res.status(200).send({ success: true, response });
+  } catch (e) {
+    console.error("Failed to process revoked-nonces/create:", e.message);
+    res.status(500).send({ success: false, error: e.message });
+  }
+});
+
 app.use(serveStatic(STATIC_PATH, { index: false }));
 
 app.use("/*", shopify.ensureInstalledOnShop(), async (_req, res, _next) => {
@@ -90,3 +108,5 @@ app.use("/*", shopify.ensureInstalledOnShop(), async (_req, res, _next) => {
 });
 
 app.listen(PORT);
+
+scheduleReferenceTimeRefresh();
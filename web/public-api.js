@@ -1,11 +1,28 @@
-import { createHmac } from "crypto";
+import { createHmac, randomBytes, sign, timingSafeEqual } from "crypto";
 import cors from "cors";
 import Web3 from "web3";
 
-import { getContractAddressesFromGate } from "./api/gates.js";
+import {
+  gateContextBuyerBinding,
+  gateContextKeyId,
+  gateContextPrivateKey,
+  gateContextSecretKey,
+  gateContextTtlSeconds,
+} from "./api/constants.js";
+import { hmacKey } from "./api/gate-keys.js";
+import { getContractAddressesFromGate, getSessionByShopDomain } from "./api/gates.js";
+import shopify from "./shopify.js";
 
 const web3 = new Web3();
 
+const CUSTOMER_EMAIL_QUERY = `
+  query CustomerEmail($id: ID!) {
+    customer(id: $id) {
+      email
+    }
+  }
+`;
+
 export function configurePublicApi(app) {
   // This should be limited to app domains that have your app installed
   const corsOptions = {
@@ -18,7 +35,11 @@ export function configurePublicApi(app) {
 
   app.post("/public/gateEvaluation", cors(corsOptions), async (req, res) => {
     // Evaluate the gate, message, and signature
-    const { shopDomain, productGid, address, message, signature, gateConfigurationGid } = req.body;
+    const { productGid, address, message, signature, gateConfigurationGid } = req.body;
+    // Requests through the app proxy carry the shop and the logged in customer,
+    // signed by Shopify. Anything else in the request could come from anyone.
+    const appProxyQuery = verifiedAppProxyQuery(req.query);
+    const shopDomain = appProxyQuery?.shop ?? req.body.shopDomain;
 
     // Verify signature
     const recoveredAddress = web3.eth.accounts.recover(message, signature);
@@ -41,25 +62,129 @@ export function configurePublicApi(app) {
     }
 
     const payload = {
-      id: gateConfigurationGid
+      id: gateConfigurationGid,
+      address: recoveredAddress.toLowerCase(),
+      ...validityPeriod(),
+      // Lets a leaked gate context be revoked through the `revoked_nonces` metafield
+      nonce: randomBytes(16).toString("hex"),
+      // Picks the tier of reactions with tiers by token count
+      tokenCount: unlockingTokens.length,
+      ...(gateContextBuyerBinding && (await authenticatedBuyer(appProxyQuery))),
     };
 
+    if (gateContextBuyerBinding && !buyerIdentityValue(payload)) {
+      res.status(403).send("Log in to unlock this gate");
+      return;
+    }
+
     const response = {gateContext: [getHmac(payload)], unlockingTokens};
     res.status(200).send(response);
   });
 }
 
 function getHmac(payload) {
-  const hmacMessage = payload.id;
-  const hmac = createHmac("sha256", "secret-key");
-  hmac.update(hmacMessage);
-  const hmacDigest = hmac.digest("hex");
+  const hmacMessage = gateContextMessage(payload);
   return {
     id: payload.id,
-    hmac: hmacDigest,
+    ...signature(hmacMessage, payload.id),
+    v: 2,
+    address: payload.address,
+    ...(payload.issuedAt && { issuedAt: payload.issuedAt, expiresAt: payload.expiresAt }),
+    nonce: payload.nonce,
+    tokenCount: payload.tokenCount,
+    ...(gateContextKeyId && { kid: gateContextKeyId }),
+    ...(gateContextBuyerBinding && { binding: gateContextBuyerBinding }),
   };
 }
 
+function signature(message, gateConfigurationId) {
+  if (gateContextPrivateKey) {
+    return { ed25519: sign(null, Buffer.from(message), gateContextPrivateKey).toString("hex") };
+  }
+
+  const hmac = createHmac("sha256", hmacKey(gateContextSecretKey, gateConfigurationId));
+  hmac.update(message);
+  return { hmac: hmac.digest("hex") };
+}
+
+// Must match `signed_message` in the functions
+function gateContextMessage(payload) {
+  const lines = ["v2", `id=${payload.id}`, `address=${payload.address}`];
+  if (payload.issuedAt) {
+    lines.push(`issuedAt=${payload.issuedAt}`, `expiresAt=${payload.expiresAt}`);
+  }
+  lines.push(`nonce=${payload.nonce}`, `tokenCount=${payload.tokenCount}`);
+  if (gateContextBuyerBinding) {
+    lines.push(`${gateContextBuyerBinding}=${buyerIdentityValue(payload)}`);
+  }
+
+  return lines.join("\n");
+}
+
+function validityPeriod() {
+  if (!gateContextTtlSeconds) {
+    return {};
+  }
+
+  const issuedAt = Math.floor(Date.now() / 1000);
+  return { issuedAt, expiresAt: issuedAt + gateContextTtlSeconds };
+}
+
+// See https://shopify.dev/docs/apps/online-store/app-proxies#calculate-a-digital-signature
+function verifiedAppProxyQuery(query) {
+  const { signature, ...params } = query;
+  if (typeof signature !== "string") {
+    return undefined;
+  }
+
+  const message = Object.keys(params)
+    .sort()
+    .map((key) => `${key}=${[].concat(params[key]).join(",")}`)
+    .join("");
+  const expected = createHmac("sha256", shopify.api.config.apiSecretKey)
+    .update(message)
+    .digest("hex");
+  if (
+    signature.length !== expected.length ||
+    !timingSafeEqual(Buffer.from(signature), Buffer.from(expected))
+  ) {
+    return undefined;
+  }
+  return params;
+}
+
+// Gate contexts are only bound to the customer that the app proxy says is logged
+// in, and to their email as the shop has it
+async function authenticatedBuyer(appProxyQuery) {
+  const customerId = appProxyQuery?.logged_in_customer_id;
+  if (!customerId) {
+    return {};
+  }
+
+  const customerGid = `gid://shopify/Customer/${customerId}`;
+  if (gateContextBuyerBinding !== "email") {
+    return { customerGid };
+  }
+
+  const client = new shopify.api.clients.Graphql({
+    session: await getSessionByShopDomain({ shopDomain: appProxyQuery.shop }),
+  });
+  const response = await client.query({
+    data: { query: CUSTOMER_EMAIL_QUERY, variables: { id: customerGid } },
+  });
+  return { customerGid, customerEmail: response.body.data.customer?.email };
+}
+
+function buyerIdentityValue(payload) {
+  switch (gateContextBuyerBinding) {
+    case "customer":
+      return payload.customerGid;
+    case "email":
+      // The functions only lowercase ASCII letters
+      return payload.customerEmail?.trim().replace(/[A-Z]/g, (letter) => letter.toLowerCase());
+  }
+}
+
 function retrieveUnlockingTokens(address, contractAddresses) {
   // This could be a lookup against a node or a 3rd party service like Alchemy
   return Promise.resolve([
@@ -7,7 +7,7 @@ let { restResources } = await import(
 // If you want IntelliSense for the rest resources, you should import them directly
 // import { restResources } from "@shopify/shopify-api/rest/admin/2022-10";
 
-const DB_PATH = `${process.cwd()}/database.sqlite`;
+export const DB_PATH = `${process.cwd()}/database.sqlite`;
 
 // The transactions with Shopify will always be marked as test transactions, unless NODE_ENV is production.
 // See the ensureBilling helper to learn more about billing in this template.